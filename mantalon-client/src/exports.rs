@@ -225,7 +225,7 @@ pub async fn proxiedFetch(ressource: JsValue, options: JsValue) -> Result<JsValu
         Ok(response) => response,
         Err(error) => {
             error!("Error sending request: {error:?}");
-            return Err(JsValue::from_str(&error.to_string()));
+            return Err(error.into());
         },
     };
 
@@ -324,8 +324,14 @@ pub async fn proxiedFetch(ressource: JsValue, options: JsValue) -> Result<JsValu
     }
 }
 
+/// Initializes the library with the URL of the server's `/mantalon-connect` endpoint.
+///
+/// If the server requires tickets, `ticket` is either a ticket or a function returning one (or a promise of one).
+/// The function is called each time a new websocket is opened, so it can refresh tickets before they expire.
+///
+/// With `multiplex`, connections to all origins are streams of a single websocket instead of a websocket each.
 #[wasm_bindgen]
-pub async fn init(mantalon_endpoint: String) {
+pub async fn init(mantalon_endpoint: String, ticket: Option<JsValue>, multiplex: Option<bool>) {
     std::panic::set_hook(Box::new(|panic_info| {
         if let Some(s) = panic_info.payload().downcast_ref::<&str>() {
             if let Some(location) = panic_info.location() {
@@ -345,6 +351,8 @@ pub async fn init(mantalon_endpoint: String) {
     }));
 
     MANTALON_ENDPOINT.set(mantalon_endpoint);
+    MANTALON_TICKET.set(ticket);
+    POOL.set_multiplex(multiplex.unwrap_or(false));
 
     debug!("Mantalon library is ready");
 }
@@ -15,6 +15,8 @@ mod compat;
 mod exports;
 mod websocket;
 use websocket::*;
+mod mux;
+use mux::*;
 mod pool;
 use pool::*;
 mod executor;
@@ -0,0 +1,371 @@
+use std::{cell::RefCell, collections::{HashMap, VecDeque}, future::Future, io::Error as IoError, pin::Pin, rc::Rc, task::{Context, Poll, Waker}};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use crate::*;
+
+// Frames of the multiplexing protocol, mirroring the server's mux.rs
+const FRAME_OPEN: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_WINDOW: u8 = 2;
+const FRAME_CLOSE: u8 = 3;
+const FRAME_FIN: u8 = 4;
+const INITIAL_WINDOW: u32 = 256 * 1024;
+const MAX_DATA_SIZE: usize = 16 * 1024;
+const CLOSE_NORMAL: u16 = 1000;
+
+fn frame(kind: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+struct StreamState {
+    received: VecDeque<u8>,
+    /// Bytes read since the last window update sent to the server.
+    consumed: u32,
+    /// Bytes the server is ready to receive.
+    send_window: u32,
+    close_info: CloseInfo,
+    /// The server will not send data anymore.
+    fin_received: bool,
+    /// We told the server we will not send data anymore.
+    fin_sent: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    on_close: Option<Box<dyn FnOnce()>>,
+}
+
+impl StreamState {
+    /// Both sides ended the stream, so there is nothing left to close.
+    fn ended(&self) -> bool {
+        self.fin_received && self.fin_sent
+    }
+
+    fn receive_fin(&mut self) {
+        self.fin_received = true;
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(on_close) = self.on_close.take() {
+            on_close();
+        }
+    }
+
+    fn close(&mut self, code: u16, reason: String) {
+        if self.close_info.is_closed() {
+            return;
+        }
+        self.close_info.set(code, reason);
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+        if let Some(on_close) = self.on_close.take() {
+            on_close();
+        }
+    }
+}
+
+#[derive(Default)]
+struct MuxShared {
+    streams: HashMap<u32, Rc<RefCell<StreamState>>>,
+    next_id: u32,
+    close_info: CloseInfo,
+    open_wakers: Vec<Waker>,
+}
+
+/// A websocket to the bare `/mantalon-connect` endpoint, carrying a stream per destination.
+pub struct MuxConnection {
+    shared: Rc<RefCell<MuxShared>>,
+    _on_open: Closure<dyn FnMut(Event)>,
+    _on_close: Closure<dyn FnMut(Event)>,
+    _on_error: Closure<dyn FnMut(Event)>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    ws: WebSocket,
+}
+
+impl MuxConnection {
+    pub fn new(url: &str) -> Result<Rc<Self>, SendRequestError> {
+        let ws = WebSocket::new(url).map_err(SendRequestError::Websocket)?;
+        ws.set_binary_type(BinaryType::Arraybuffer);
+        let shared = Rc::new(RefCell::new(MuxShared::default()));
+
+        // Create open and error listeners, which both end the wait for the websocket to open
+        let shared2 = Rc::clone(&shared);
+        let on_open = Closure::wrap(Box::new(move |_| {
+            shared2.borrow_mut().open_wakers.drain(..).for_each(Waker::wake);
+        }) as Box<dyn FnMut(Event)>);
+        ws.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        let shared2 = Rc::clone(&shared);
+        let on_error = Closure::wrap(Box::new(move |_| {
+            error!("Multiplexed websocket error");
+            shared2.borrow_mut().open_wakers.drain(..).for_each(Waker::wake);
+        }) as Box<dyn FnMut(Event)>);
+        ws.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+
+        // Create close listener, closing all streams with the code of the websocket
+        let shared2 = Rc::clone(&shared);
+        let on_close = Closure::wrap(Box::new(move |event: Event| {
+            let (code, reason) = match event.dyn_into::<CloseEvent>() {
+                Ok(event) => (event.code(), event.reason()),
+                Err(_) => (1005, String::new()),
+            };
+            log!("Multiplexed websocket closed ({code}): {reason}");
+            let mut shared = shared2.borrow_mut();
+            shared.close_info.set(code, reason.clone());
+            shared.open_wakers.drain(..).for_each(Waker::wake);
+            let streams: Vec<_> = shared.streams.drain().map(|(_, stream)| stream).collect();
+            drop(shared);
+            for stream in streams {
+                stream.borrow_mut().close(code, reason.clone());
+            }
+        }) as Box<dyn FnMut(Event)>);
+        ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+        // Create message receiver, dispatching frames to their stream
+        let shared2 = Rc::clone(&shared);
+        let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let Ok(data) = event.data().dyn_into::<js_sys::ArrayBuffer>() else {
+                error!("Received non-binary message from multiplexed websocket");
+                return;
+            };
+            let message = js_sys::Uint8Array::new(&data).to_vec();
+            let [kind, a, b, c, d, payload @ ..] = message.as_slice() else {
+                error!("Received truncated frame from multiplexed websocket");
+                return;
+            };
+            let stream_id = u32::from_be_bytes([*a, *b, *c, *d]);
+            let stream = match *kind {
+                FRAME_CLOSE => shared2.borrow_mut().streams.remove(&stream_id),
+                _ => shared2.borrow().streams.get(&stream_id).cloned(),
+            };
+            let Some(stream) = stream else {
+                return;
+            };
+            let mut stream = stream.borrow_mut();
+            match *kind {
+                FRAME_DATA => {
+                    stream.received.extend(payload);
+                    if let Some(waker) = stream.read_waker.take() {
+                        waker.wake();
+                    }
+                }
+                FRAME_WINDOW => {
+                    let Ok(increment) = <[u8; 4]>::try_from(payload).map(u32::from_be_bytes) else {
+                        error!("Received invalid window update from multiplexed websocket");
+                        return;
+                    };
+                    stream.send_window = stream.send_window.saturating_add(increment);
+                    if let Some(waker) = stream.write_waker.take() {
+                        waker.wake();
+                    }
+                }
+                FRAME_CLOSE => {
+                    let (code, reason) = match payload {
+                        [a, b, reason @ ..] => (u16::from_be_bytes([*a, *b]), String::from_utf8_lossy(reason).into_owned()),
+                        _ => (1005, String::new()),
+                    };
+                    debug!("Stream {stream_id} closed ({code}): {reason}");
+                    stream.close(code, reason);
+                }
+                FRAME_FIN => {
+                    stream.receive_fin();
+                    if stream.ended() {
+                        shared2.borrow_mut().streams.remove(&stream_id);
+                    }
+                }
+                kind => error!("Received unknown frame type {kind} from multiplexed websocket"),
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+        ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        Ok(Rc::new(MuxConnection {
+            shared,
+            _on_open: on_open,
+            _on_close: on_close,
+            _on_error: on_error,
+            _on_message: on_message,
+            ws,
+        }))
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self.ws.ready_state(), WebSocket::CLOSING | WebSocket::CLOSED)
+    }
+
+    fn send(&self, frame: &[u8]) -> Result<(), JsValue> {
+        self.ws.send_with_u8_array(frame)
+    }
+
+    /// Opens a stream to a multiaddr such as `/dns/example.com/tcp/443`, once the websocket is open.
+    ///
+    /// `on_close` is called when the server ends or closes the stream, or because the websocket closed.
+    pub async fn open_stream(self: &Rc<Self>, multiaddr: &str, on_close: impl FnOnce() + 'static) -> Result<MuxStream, SendRequestError> {
+        MuxReadyFut(self).await;
+        let ready_state = self.ws.ready_state();
+        if ready_state != WebSocket::OPEN {
+            let error = self.shared.borrow().close_info.error();
+            return Err(error.unwrap_or_else(|| SendRequestError::Websocket(JsValue::from_str(&format!("Websocket not open ({ready_state})")))));
+        }
+
+        let mut shared = self.shared.borrow_mut();
+        let mut stream_id = shared.next_id;
+        while shared.streams.contains_key(&stream_id) {
+            stream_id = stream_id.wrapping_add(1);
+        }
+        shared.next_id = stream_id.wrapping_add(1);
+        let state = Rc::new(RefCell::new(StreamState {
+            received: VecDeque::new(),
+            consumed: 0,
+            send_window: INITIAL_WINDOW,
+            close_info: CloseInfo::default(),
+            fin_received: false,
+            fin_sent: false,
+            read_waker: None,
+            write_waker: None,
+            on_close: Some(Box::new(on_close)),
+        }));
+        shared.streams.insert(stream_id, Rc::clone(&state));
+        drop(shared);
+
+        let stream = MuxStream { connection: Rc::clone(self), stream_id, state };
+        self.send(&frame(FRAME_OPEN, stream_id, multiaddr.as_bytes())).map_err(SendRequestError::Websocket)?;
+        Ok(stream)
+    }
+}
+
+impl Drop for MuxConnection {
+    fn drop(&mut self) {
+        self.ws.set_onclose(None);
+        self.ws.set_onerror(None);
+        self.ws.set_onmessage(None);
+        self.ws.set_onopen(None);
+        let _ = self.ws.close();
+    }
+}
+
+struct MuxReadyFut<'a>(&'a MuxConnection);
+
+impl<'a> Future for MuxReadyFut<'a> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0.ws.ready_state() != WebSocket::CONNECTING {
+            Poll::Ready(())
+        } else {
+            self.0.shared.borrow_mut().open_wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+/// A stream multiplexed over a [`MuxConnection`], used like a [`WrappedWebSocket`].
+pub struct MuxStream {
+    connection: Rc<MuxConnection>,
+    stream_id: u32,
+    state: Rc<RefCell<StreamState>>,
+}
+
+unsafe impl Send for MuxStream {}
+unsafe impl Sync for MuxStream {}
+
+impl MuxStream {
+    pub fn close_info(&self) -> CloseInfo {
+        self.state.borrow().close_info.clone()
+    }
+
+    /// Closes the stream on both sides, unless it already is or both sides ended it.
+    fn close(&self) {
+        self.connection.shared.borrow_mut().streams.remove(&self.stream_id);
+        let mut state = self.state.borrow_mut();
+        if !state.close_info.is_closed() && !state.ended() {
+            let _ = self.connection.send(&frame(FRAME_CLOSE, self.stream_id, &CLOSE_NORMAL.to_be_bytes()));
+            state.close(CLOSE_NORMAL, String::new());
+        }
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize, IoError>> {
+        let mut state = self.state.borrow_mut();
+        if state.close_info.is_closed() {
+            return Poll::Ready(Err(IoError::other("Stream closed")));
+        }
+        if state.fin_sent {
+            return Poll::Ready(Err(IoError::other("Stream shut down")));
+        }
+        if state.send_window == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(state.send_window as usize).min(MAX_DATA_SIZE);
+        match self.connection.send(&frame(FRAME_DATA, self.stream_id, &buf[..n])) {
+            Ok(_) => {
+                state.send_window -= n as u32;
+                Poll::Ready(Ok(n))
+            }
+            Err(err) => {
+                error!("Error sending data over multiplexed websocket: {:?}", err);
+                Poll::Ready(Err(IoError::other("Error sending data over multiplexed websocket")))
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Ends our side of the stream, while what the server sends can still be read.
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        let mut state = self.state.borrow_mut();
+        if state.close_info.is_closed() || state.fin_sent {
+            return Poll::Ready(Ok(()));
+        }
+        if let Err(err) = self.connection.send(&frame(FRAME_FIN, self.stream_id, &[])) {
+            error!("Error sending data over multiplexed websocket: {:?}", err);
+            return Poll::Ready(Err(IoError::other("Error sending data over multiplexed websocket")));
+        }
+        state.fin_sent = true;
+        if state.ended() {
+            self.connection.shared.borrow_mut().streams.remove(&self.stream_id);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let mut state = self.state.borrow_mut();
+        let n = buf.remaining().min(state.received.len());
+        if n == 0 {
+            if state.close_info.is_closed() || state.fin_received {
+                return Poll::Ready(Ok(()));
+            }
+            state.read_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let (front, back) = state.received.as_slices();
+        let from_front = n.min(front.len());
+        buf.put_slice(&front[..from_front]);
+        buf.put_slice(&back[..n - from_front]);
+        state.received.drain(..n);
+
+        // Let the server send more once half of the window has been read
+        state.consumed += n as u32;
+        if state.consumed >= INITIAL_WINDOW / 2 && !state.close_info.is_closed() && !state.fin_received {
+            let _ = self.connection.send(&frame(FRAME_WINDOW, self.stream_id, &state.consumed.to_be_bytes()));
+            state.consumed = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
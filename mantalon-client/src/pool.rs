@@ -1,4 +1,4 @@
-use std::{cell::RefCell, collections::HashMap, io::Error as IoError, rc::Rc, time::Duration};
+use std::{cell::{Cell, RefCell}, collections::HashMap, io::Error as IoError, rc::Rc, time::Duration};
 use http::{Request, Response, Uri};
 use tokio::sync::RwLock;
 use tokio_rustls::rustls::pki_types::InvalidDnsNameError;
@@ -8,14 +8,21 @@ use lazy_static::lazy_static;
 lazy_static!{
     pub static ref POOL: Pool = Pool {
         connections: Default::default(),
+        multiplex: Cell::new(false),
+        mux: RefCell::new(None),
     };
 
     pub static ref MANTALON_ENDPOINT: EndpointUrl = EndpointUrl(Rc::new(RefCell::new(String::new())));
+
+    pub static ref MANTALON_TICKET: TicketSource = TicketSource(Rc::new(RefCell::new(None)));
 }
 
 #[allow(clippy::type_complexity)]
 pub struct Pool {
     connections: Rc<RwLock<HashMap<String, Option<SendRequest>>>>,
+    /// Whether connections are opened as streams of a single websocket.
+    multiplex: Cell<bool>,
+    mux: RefCell<Option<Rc<MuxConnection>>>,
 }
 
 unsafe impl Send for Pool {}
@@ -30,9 +37,56 @@ impl EndpointUrl {
     }
 }
 
+/// The ticket presented to the server, either as a string or as a function returning one.
+pub struct TicketSource(Rc<RefCell<Option<JsValue>>>);
+unsafe impl Send for TicketSource {}
+unsafe impl Sync for TicketSource {}
+impl TicketSource {
+    pub fn set(&self, ticket: Option<JsValue>) {
+        *self.0.borrow_mut() = ticket.filter(|t| !t.is_null() && !t.is_undefined());
+    }
+
+    /// Returns the current ticket, calling the refresh function if there is one.
+    /// The function can return a string or a promise resolving to a string.
+    pub async fn get(&self) -> Result<Option<String>, SendRequestError> {
+        let Some(source) = self.0.borrow().clone() else {
+            return Ok(None);
+        };
+        if let Some(ticket) = source.as_string() {
+            return Ok(Some(ticket));
+        }
+        let Some(refresh) = source.dyn_ref::<js_sys::Function>() else {
+            return Err(SendRequestError::InvalidTicket);
+        };
+        let mut ticket = refresh.call0(&JsValue::NULL).map_err(SendRequestError::TicketRefresh)?;
+        if let Some(promise) = ticket.dyn_ref::<Promise>() {
+            ticket = JsFuture::from(promise.clone()).await.map_err(SendRequestError::TicketRefresh)?;
+        }
+        ticket.as_string().map(Some).ok_or(SendRequestError::InvalidTicket)
+    }
+}
+
+// Close codes the server uses to report why it could not open or had to end a relay, mirroring the server's errors.rs
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_MESSAGE_TOO_LARGE: u16 = 1009;
+const CLOSE_INVALID_ADDRESS: u16 = 4400;
+const CLOSE_UNAUTHORIZED: u16 = 4401;
+const CLOSE_POLICY_DENIED: u16 = 4403;
+const CLOSE_DNS_FAILED: u16 = 4404;
+const CLOSE_IDLE_TIMEOUT: u16 = 4408;
+const CLOSE_TERMINATED: u16 = 4410;
+const CLOSE_RATE_LIMITED: u16 = 4429;
+const CLOSE_CONNECT_FAILED: u16 = 4500;
+const CLOSE_CONNECT_REFUSED: u16 = 4502;
+const CLOSE_UNAVAILABLE: u16 = 4503;
+const CLOSE_CONNECT_TIMEOUT: u16 = 4504;
+const CLOSE_QUOTA_EXCEEDED: u16 = 4509;
+
 #[derive(Debug)]
 pub enum SendRequestError {
     EndpointNotSet,
+    InvalidTicket,
+    TicketRefresh(JsValue),
     NoScheme,
     NoCommonProtocol,
     UnsupportedScheme(String),
@@ -44,12 +98,28 @@ pub enum SendRequestError {
     ConnectionNotReady,
     HttpHandshake(hyper::Error),
     Hyper(hyper::Error),
+    InvalidAddress(String),
+    Unauthorized(String),
+    PolicyDenied(String),
+    DnsFailed(String),
+    RateLimited(String),
+    QuotaExceeded(String),
+    ServerUnavailable(String),
+    ConnectRefused(String),
+    ConnectTimeout(String),
+    ConnectFailed(String),
+    IdleTimeout(String),
+    GoingAway(String),
+    MessageTooLarge(String),
+    Terminated(String),
 }
 
 impl std::fmt::Display for SendRequestError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             SendRequestError::EndpointNotSet => write!(f, "Endpoint not set. Please call init before sending requests"),
+            SendRequestError::InvalidTicket => write!(f, "The ticket must be a string or a function returning one"),
+            SendRequestError::TicketRefresh(e) => write!(f, "Error refreshing ticket: {e:?}"),
             SendRequestError::NoScheme => write!(f, "No scheme in URI"),
             SendRequestError::NoCommonProtocol => write!(f, "The server and client do not have a common protocol"),
             SendRequestError::UnsupportedScheme(scheme) => write!(f, "Unsupported scheme: {scheme}"),
@@ -61,12 +131,91 @@ impl std::fmt::Display for SendRequestError {
             SendRequestError::ConnectionNotReady => write!(f, "Connection not ready"),
             SendRequestError::HttpHandshake(e) => write!(f, "Error in HTTP handshake: {e}"),
             SendRequestError::Hyper(e) => write!(f, "Hyper error: {e}"),
+            SendRequestError::InvalidAddress(reason)
+            | SendRequestError::Unauthorized(reason)
+            | SendRequestError::PolicyDenied(reason)
+            | SendRequestError::DnsFailed(reason)
+            | SendRequestError::RateLimited(reason)
+            | SendRequestError::QuotaExceeded(reason)
+            | SendRequestError::ServerUnavailable(reason)
+            | SendRequestError::ConnectRefused(reason)
+            | SendRequestError::ConnectTimeout(reason)
+            | SendRequestError::ConnectFailed(reason) => write!(f, "The server could not open the connection: {reason}"),
+            SendRequestError::IdleTimeout(reason) => write!(f, "The server closed the idle connection: {reason}"),
+            SendRequestError::GoingAway(reason) => write!(f, "The server closed the connection as it is shutting down: {reason}"),
+            SendRequestError::MessageTooLarge(reason) => write!(f, "The server closed the connection after a message larger than it accepts: {reason}"),
+            SendRequestError::Terminated(reason) => write!(f, "The server operator terminated the connection: {reason}"),
         }
     }
 }
 
 impl std::error::Error for SendRequestError {}
 
+impl SendRequestError {
+    /// Decodes the close frame of a websocket the server could not open a relay for.
+    pub fn from_close_frame(code: u16, reason: String) -> Option<SendRequestError> {
+        match code {
+            CLOSE_INVALID_ADDRESS => Some(SendRequestError::InvalidAddress(reason)),
+            CLOSE_UNAUTHORIZED => Some(SendRequestError::Unauthorized(reason)),
+            CLOSE_POLICY_DENIED => Some(SendRequestError::PolicyDenied(reason)),
+            CLOSE_DNS_FAILED => Some(SendRequestError::DnsFailed(reason)),
+            CLOSE_RATE_LIMITED => Some(SendRequestError::RateLimited(reason)),
+            CLOSE_QUOTA_EXCEEDED => Some(SendRequestError::QuotaExceeded(reason)),
+            CLOSE_UNAVAILABLE => Some(SendRequestError::ServerUnavailable(reason)),
+            CLOSE_CONNECT_REFUSED => Some(SendRequestError::ConnectRefused(reason)),
+            CLOSE_CONNECT_TIMEOUT => Some(SendRequestError::ConnectTimeout(reason)),
+            CLOSE_CONNECT_FAILED => Some(SendRequestError::ConnectFailed(reason)),
+            CLOSE_IDLE_TIMEOUT => Some(SendRequestError::IdleTimeout(reason)),
+            CLOSE_TERMINATED => Some(SendRequestError::Terminated(reason)),
+            CLOSE_GOING_AWAY => Some(SendRequestError::GoingAway(reason)),
+            CLOSE_MESSAGE_TOO_LARGE => Some(SendRequestError::MessageTooLarge(reason)),
+            _ => None,
+        }
+    }
+
+    /// The name of the variant, used as the `name` of the JS error.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SendRequestError::EndpointNotSet => "EndpointNotSet",
+            SendRequestError::InvalidTicket => "InvalidTicket",
+            SendRequestError::TicketRefresh(_) => "TicketRefresh",
+            SendRequestError::NoScheme => "NoScheme",
+            SendRequestError::NoCommonProtocol => "NoCommonProtocol",
+            SendRequestError::UnsupportedScheme(_) => "UnsupportedScheme",
+            SendRequestError::NoHost => "NoHost",
+            SendRequestError::ServerNameParseError(_) => "ServerNameParseError",
+            SendRequestError::UnsupportedServerNameType => "UnsupportedServerNameType",
+            SendRequestError::Websocket(_) => "Websocket",
+            SendRequestError::TlsConnect(_) => "TlsConnect",
+            SendRequestError::ConnectionNotReady => "ConnectionNotReady",
+            SendRequestError::HttpHandshake(_) => "HttpHandshake",
+            SendRequestError::Hyper(_) => "Hyper",
+            SendRequestError::InvalidAddress(_) => "InvalidAddress",
+            SendRequestError::Unauthorized(_) => "Unauthorized",
+            SendRequestError::PolicyDenied(_) => "PolicyDenied",
+            SendRequestError::DnsFailed(_) => "DnsFailed",
+            SendRequestError::RateLimited(_) => "RateLimited",
+            SendRequestError::QuotaExceeded(_) => "QuotaExceeded",
+            SendRequestError::ServerUnavailable(_) => "ServerUnavailable",
+            SendRequestError::ConnectRefused(_) => "ConnectRefused",
+            SendRequestError::ConnectTimeout(_) => "ConnectTimeout",
+            SendRequestError::ConnectFailed(_) => "ConnectFailed",
+            SendRequestError::IdleTimeout(_) => "IdleTimeout",
+            SendRequestError::GoingAway(_) => "GoingAway",
+            SendRequestError::MessageTooLarge(_) => "MessageTooLarge",
+            SendRequestError::Terminated(_) => "Terminated",
+        }
+    }
+}
+
+impl From<SendRequestError> for JsValue {
+    fn from(error: SendRequestError) -> JsValue {
+        let js_error = js_sys::Error::new(&error.to_string());
+        js_error.set_name(error.name());
+        js_error.into()
+    }
+}
+
 fn get_server(uri: &Uri) -> Result<(String, ServerName<'static>), SendRequestError> {
     let port = match uri.port_u16() {
         Some(port) => port,
@@ -80,7 +229,7 @@ fn get_server(uri: &Uri) -> Result<(String, ServerName<'static>), SendRequestErr
     let host = uri.authority().map(|a| a.host().to_owned()).ok_or(SendRequestError::NoHost)?;
     let server_name = ServerName::try_from(host).map_err(SendRequestError::ServerNameParseError)?;
     let multiaddr = match &server_name {
-        ServerName::DnsName(domain) => format!("dnsaddr/{}/tcp/{port}", domain.as_ref()),
+        ServerName::DnsName(domain) => format!("dns/{}/tcp/{port}", domain.as_ref()),
         ServerName::IpAddress(RustlsIpAddr::V4(ip)) => {
             let [a, b, c, d] = ip.as_ref();
             format!("ip4/{a}.{b}.{c}.{d}/tcp/{port}")
@@ -98,61 +247,134 @@ fn get_server(uri: &Uri) -> Result<(String, ServerName<'static>), SendRequestErr
 }
 
 
+/// Handshakes with the HTTP server at the end of a relayed stream, over TLS for https.
+async fn http_handshake<S>(stream: S, server_name: ServerName<'static>, https: bool) -> Result<SendRequest, SendRequestError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync + 'static,
+{
+    if https {
+        // Encrypt stream :)
+        let mut root_cert_store = RootCertStore::empty();
+        root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let mut config = ClientConfig::builder_with_protocol_versions(tokio_rustls::rustls::ALL_VERSIONS)
+            .with_root_certificates(root_cert_store)
+            .with_no_client_auth();
+        config.alpn_protocols.push(b"h2".to_vec());
+        config.alpn_protocols.push(b"http/1.1".to_vec());
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = connector.connect(server_name, stream).await.map_err(SendRequestError::TlsConnect)?;
+        let alpn_protocol = stream.get_ref().1.alpn_protocol().map(|s| s.to_vec());
+        let stream = TokioIo::new(stream);
+
+        match alpn_protocol.as_deref() {
+            Some(b"http/1.1") => SendRequest::new_h1(stream).await.map_err(SendRequestError::HttpHandshake),
+            Some(b"h2") => SendRequest::new_h2(stream).await.map_err(SendRequestError::HttpHandshake),
+            _ => Err(SendRequestError::NoCommonProtocol),
+        }
+    } else {
+        // Don't encrypt stream :(
+        let stream = TokioIo::new(stream);
+        SendRequest::new_h1(stream).await.map_err(SendRequestError::HttpHandshake)
+    }
+}
+
 impl Pool {
+    pub fn set_multiplex(&self, multiplex: bool) {
+        self.multiplex.set(multiplex);
+        self.mux.borrow_mut().take();
+    }
+
+    /// Returns the multiplexed websocket, opening a new one if there is none or it was closed.
+    async fn mux_connection(&self, endpoint: &str) -> Result<Rc<MuxConnection>, SendRequestError> {
+        if let Some(mux) = self.mux.borrow().as_ref().filter(|mux| !mux.is_closed()) {
+            return Ok(Rc::clone(mux));
+        }
+
+        let mut ws_url = format!("{}?report=close", endpoint.trim_end_matches('/'));
+        if let Some(ticket) = MANTALON_TICKET.get().await? {
+            let ticket = String::from(js_sys::encode_uri_component(&ticket));
+            ws_url.push_str(&format!("&ticket={ticket}"));
+        }
+
+        // Another request may have opened one while we were getting the ticket
+        if let Some(mux) = self.mux.borrow().as_ref().filter(|mux| !mux.is_closed()) {
+            return Ok(Rc::clone(mux));
+        }
+        debug!("Opening multiplexed websocket to {endpoint}");
+        let mux = MuxConnection::new(&ws_url)?;
+        *self.mux.borrow_mut() = Some(Rc::clone(&mux));
+        Ok(mux)
+    }
+
     async fn send_request_new_stream(&self, request: Request<MantalonBody>)  -> Result<Response<Incoming>, SendRequestError> {
         let uri = request.uri();
         let (multiaddr, server_name) = get_server(uri)?;
+        let https = uri.scheme().map(|s| s.as_str()).unwrap_or_default() == "https";
         debug!("Opening connection to {}", multiaddr);
 
         // Get the endpoint
-        let mantalon_endpoint = MANTALON_ENDPOINT.0.borrow();
+        let mantalon_endpoint = MANTALON_ENDPOINT.0.borrow().clone();
         if mantalon_endpoint.is_empty() {
             return Err(SendRequestError::EndpointNotSet);
         }
-        let ws_url = match mantalon_endpoint.ends_with('/') {
-            true => format!("{mantalon_endpoint}{multiaddr}"),
-            false => format!("{mantalon_endpoint}/{multiaddr}"),
-        };
-        std::mem::drop(mantalon_endpoint);
 
-        // Open the websocket
         let connections2 = Rc::clone(&self.connections);
         let multiaddr2 = multiaddr.clone();
         let on_close = || spawn_local(async move { connections2.write().await.remove(&multiaddr2); });
-        let websocket = WebSocket::new(&ws_url).map_err(SendRequestError::Websocket)?;
-
-        // Wrap the websocket
-        let websocket = WrappedWebSocket::new(websocket, on_close);
-        websocket.ready().await;
-        let ready_state = websocket.ready_state();
-        if websocket.ready_state() != WebSocket::OPEN {
-            return Err(SendRequestError::Websocket(JsValue::from_str(&format!("Websocket not open ({ready_state})"))));
-        }
+        if self.multiplex.get() {
+            // Open a stream over the shared websocket
+            let mux = self.mux_connection(&mantalon_endpoint).await?;
+            let stream = mux.open_stream(&format!("/{multiaddr}"), on_close).await?;
+            let close_info = stream.close_info();
+            let result = self.send_request_over(stream, server_name, https, multiaddr, request).await;
+            let result = result.map_err(|e| close_info.error().unwrap_or(e));
 
-        let mut request_sender = if uri.scheme().map(|s| s.as_str()).unwrap_or_default() == "https" {
-            // Encrypt stream :)
-            let mut root_cert_store = RootCertStore::empty();
-            root_cert_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
-            let mut config = ClientConfig::builder_with_protocol_versions(tokio_rustls::rustls::ALL_VERSIONS)
-                .with_root_certificates(root_cert_store)
-                .with_no_client_auth();
-            config.alpn_protocols.push(b"h2".to_vec());
-            config.alpn_protocols.push(b"http/1.1".to_vec());
-            let connector = TlsConnector::from(Arc::new(config));
-            let stream = connector.connect(server_name, websocket).await.map_err(SendRequestError::TlsConnect)?;
-            let alpn_protocol = stream.get_ref().1.alpn_protocol().map(|s| s.to_vec());
-            let stream = TokioIo::new(stream);
-            
-            match alpn_protocol.as_deref() {
-                Some(b"http/1.1") => SendRequest::new_h1(stream).await.map_err(SendRequestError::HttpHandshake)?,
-                Some(b"h2") => SendRequest::new_h2(stream).await.map_err(SendRequestError::HttpHandshake)?,
-                _ => return Err(SendRequestError::NoCommonProtocol),
+            // The ticket of the websocket is presented for every stream, so get a new one when it is refused
+            if let Err(SendRequestError::Unauthorized(_)) = &result {
+                self.mux.borrow_mut().take();
             }
+            result
         } else {
-            // Don't encrypt stream :(
-            let stream = TokioIo::new(websocket);
-            SendRequest::new_h1(stream).await.map_err(SendRequestError::HttpHandshake)?
-        };
+            let mut ws_url = match mantalon_endpoint.ends_with('/') {
+                true => format!("{mantalon_endpoint}{multiaddr}"),
+                false => format!("{mantalon_endpoint}/{multiaddr}"),
+            };
+
+            // Ask for failures to be reported in close frames, as the status of a failed upgrade is hidden from us
+            ws_url.push_str("?report=close");
+
+            // Attach the ticket
+            if let Some(ticket) = MANTALON_TICKET.get().await? {
+                let ticket = String::from(js_sys::encode_uri_component(&ticket));
+                ws_url.push_str(&format!("&ticket={ticket}"));
+            }
+
+            // Open the websocket
+            let websocket = WebSocket::new(&ws_url).map_err(SendRequestError::Websocket)?;
+
+            // Wrap the websocket
+            let websocket = WrappedWebSocket::new(websocket, on_close);
+            let close_info = websocket.close_info();
+            let result = async {
+                websocket.ready().await;
+                let ready_state = websocket.ready_state();
+                if websocket.ready_state() != WebSocket::OPEN {
+                    return Err(SendRequestError::Websocket(JsValue::from_str(&format!("Websocket not open ({ready_state})"))));
+                }
+                self.send_request_over(websocket, server_name, https, multiaddr, request).await
+            }.await;
+
+            // Prefer the reason the server gave when closing the websocket over the resulting IO errors
+            result.map_err(|e| close_info.error().unwrap_or(e))
+        }
+    }
+
+    /// Handshakes over a new relayed stream, stores the connection and sends the request on it.
+    async fn send_request_over<S>(&self, stream: S, server_name: ServerName<'static>, https: bool, multiaddr: String, request: Request<MantalonBody>) -> Result<Response<Incoming>, SendRequestError>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        let mut request_sender = http_handshake(stream, server_name, https).await?;
 
         // Store the connection
         let request_sender2 = request_sender.clone();
@@ -1,4 +1,4 @@
-use std::{cell::RefCell, collections::VecDeque, future::Future, io::{Error as IoError, ErrorKind as IoErrorKind}, pin::Pin, rc::Rc, task::{Context, Poll, Waker}};
+use std::{cell::{Cell, RefCell}, collections::VecDeque, future::Future, io::Error as IoError, pin::Pin, rc::Rc, task::{Context, Poll, Waker}};
 use tokio::io::{AsyncWrite, AsyncRead};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::*;
@@ -27,8 +27,31 @@ async fn blob_into_bytes(blob: Blob) -> Vec<u8> {
 //     array.to_vec() // OPTIM
 // }
 
+/// The close code and reason the websocket was closed with, if it was.
+#[derive(Clone, Default)]
+pub struct CloseInfo(Rc<RefCell<Option<(u16, String)>>>);
+
+impl CloseInfo {
+    pub fn is_closed(&self) -> bool {
+        self.0.borrow().is_some()
+    }
+
+    pub fn set(&self, code: u16, reason: String) {
+        *self.0.borrow_mut() = Some((code, reason));
+    }
+
+    /// Decodes the close frame the server sends when it could not open the relay.
+    pub fn error(&self) -> Option<SendRequestError> {
+        let (code, reason) = self.0.borrow().clone()?;
+        SendRequestError::from_close_frame(code, reason)
+    }
+}
+
 pub struct WrappedWebSocket {
     buffer: Rc<RefCell<VecDeque<u8>>>,
+    /// Messages received but not yet converted into bytes, which must be read before reporting the end of the stream.
+    pending_messages: Rc<Cell<usize>>,
+    close_info: CloseInfo,
     read_waker: Rc<RefCell<Option<Waker>>>,
     open_waker: Rc<RefCell<Option<Waker>>>,
     _on_open: Closure<dyn FnMut(Event)>,
@@ -57,11 +80,18 @@ impl WrappedWebSocket {
         ws.set_onopen(Some(on_open.as_ref().unchecked_ref()));
 
         // Create close listener
+        let close_info = CloseInfo::default();
+        let close_info2 = close_info.clone();
         let mut on_close_inner = Some(on_close);
         let read_waker2 = Rc::clone(&read_waker);
         let open_waker3 = Rc::clone(&open_waker);
-        let on_close = Closure::wrap(Box::new(move |_| {
-            log!("Websocket closed");
+        let on_close = Closure::wrap(Box::new(move |event: Event| {
+            let (code, reason) = match event.dyn_into::<CloseEvent>() {
+                Ok(event) => (event.code(), event.reason()),
+                Err(_) => (1005, String::new()),
+            };
+            log!("Websocket closed ({code}): {reason}");
+            close_info2.set(code, reason);
             if let Some(waker) = read_waker2.borrow_mut().as_ref() {
                 waker.wake_by_ref();
             }
@@ -89,15 +119,20 @@ impl WrappedWebSocket {
         ws.set_onerror(Some(on_error.as_ref().unchecked_ref()));
 
         // Create message receiver
+        let pending_messages = Rc::new(Cell::new(0));
+        let pending_messages2 = Rc::clone(&pending_messages);
         let buffer2 = Rc::clone(&buffer);
         let read_waker2 = Rc::clone(&read_waker);
         let on_message = Closure::wrap(Box::new(move |event: MessageEvent| {
             if let Ok(blob) = event.data().dyn_into::<Blob>() {
                 let buffer3 = Rc::clone(&buffer2);
                 let read_waker3 = Rc::clone(&read_waker2);
+                let pending_messages3 = Rc::clone(&pending_messages2);
+                pending_messages3.set(pending_messages3.get() + 1);
                 spawn_local(async move {
                     let data = blob_into_bytes(blob).await;
                     buffer3.borrow_mut().extend(data);
+                    pending_messages3.set(pending_messages3.get() - 1);
                     if let Some(waker) = read_waker3.borrow_mut().as_ref() {
                         waker.wake_by_ref();
                     }
@@ -110,6 +145,8 @@ impl WrappedWebSocket {
 
         WrappedWebSocket {
             buffer,
+            pending_messages,
+            close_info,
             read_waker,
             open_waker,
             _on_open: on_open,
@@ -123,6 +160,10 @@ impl WrappedWebSocket {
     pub fn ready_state(&self) -> u16 {
         self.ws.ready_state()
     }
+
+    pub fn close_info(&self) -> CloseInfo {
+        self.close_info.clone()
+    }
 }
 
 pub struct WebsocketReadyFut<'a>(&'a WrappedWebSocket);
@@ -141,7 +182,7 @@ impl<'a> Future for WebsocketReadyFut<'a> {
 }
 
 impl WrappedWebSocket {
-    pub fn ready(&self) -> WebsocketReadyFut {
+    pub fn ready(&self) -> WebsocketReadyFut<'_> {
         WebsocketReadyFut(self)
     }
 }
@@ -152,7 +193,7 @@ impl AsyncWrite for WrappedWebSocket {
             Ok(_) => Poll::Ready(Ok(buf.len())),
             Err(err) => {
                 error!("Error sending data over websocket: {:?}", err);
-                Poll::Ready(Err(IoError::new(IoErrorKind::Other, "Error sending data over websocket")))
+                Poll::Ready(Err(IoError::other("Error sending data over websocket")))
             }
         }
     }
@@ -166,7 +207,7 @@ impl AsyncWrite for WrappedWebSocket {
             Ok(_) => Poll::Ready(Ok(())),
             Err(err) => {
                 error!("Error closing websocket: {:?}", err);
-                Poll::Ready(Err(IoError::new(IoErrorKind::Other, "Error closing websocket")))
+                Poll::Ready(Err(IoError::other("Error closing websocket")))
             }
         }
     }
@@ -194,7 +235,7 @@ impl AsyncRead for WrappedWebSocket {
                 break;
             }
         }
-        if n == 0 {
+        if n == 0 && (!self.close_info.is_closed() || self.pending_messages.get() > 0) {
             return Poll::Pending;
         }
         Poll::Ready(Ok(()))
@@ -363,6 +363,12 @@ export class Manifest {
     /// The endpoint to connect to the Mantalon server
     server_endpoint: string;
 
+    /// The ticket to present to the Mantalon server, if it requires one
+    server_ticket?: string;
+
+    /// Whether to carry all connections over a single websocket to the Mantalon server
+    server_multiplex?: boolean;
+
     /// Instructs the portal to override URLs.
     /// If a URL matches any of these patterns, the portal will load the specified URL instead, without any detectable redirection.
     rewrites?: RewriteConfig[];
@@ -408,6 +414,22 @@ export class Manifest {
         }
         this.server_endpoint = data.server_endpoint;
 
+        // Validate and set optional server_ticket
+        if (data.server_ticket !== undefined) {
+            if (typeof data.server_ticket !== "string") {
+                throw new Error("Manifest.server_ticket must be a string");
+            }
+            this.server_ticket = data.server_ticket;
+        }
+
+        // Validate and set optional server_multiplex
+        if (data.server_multiplex !== undefined) {
+            if (typeof data.server_multiplex !== "boolean") {
+                throw new Error("Manifest.server_multiplex must be a boolean");
+            }
+            this.server_multiplex = data.server_multiplex;
+        }
+
         // Validate and set optional rewrites
         if (data.rewrites) {
             if (!Array.isArray(data.rewrites)) {
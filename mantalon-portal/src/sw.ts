@@ -238,7 +238,7 @@ try {
     async function run() {
         await wasm_bindgen("/mantalon/mantalon_client_bg.wasm");
         manifest = await loadingManifest;
-        await init(manifest.server_endpoint);
+        await init(manifest.server_endpoint, manifest.server_ticket, manifest.server_multiplex);
         initSuccess = true;
         globalProxiedFetch = proxiedFetch;1
         console.log("Successfully initialized Mantalon. Proxying ");
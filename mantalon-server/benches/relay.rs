@@ -0,0 +1,145 @@
+//! Measures the throughput of TCP relays through a local server, with `cargo bench -p mantalon-server`.
+//!
+//! The server binary is started on a free port, and relays data between a websocket client and a local TCP endpoint
+//! that either discards what it receives (uploads) or sends a fixed amount of data (downloads).
+//! Set `MANTALON_BENCH_SERVER` to the path of another build of the server to compare them.
+
+use std::{net::SocketAddr, process::{Child, Command, Stdio}, time::{Duration, Instant}};
+use soketto::{handshake::{Client, ServerResponse}, Receiver, Sender};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::oneshot};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
+
+/// How many bytes each run transfers.
+const TRANSFER_SIZE: usize = 256 * 1024 * 1024;
+
+/// How many times each scenario is run, the best run being reported.
+const RUNS: usize = 3;
+
+type WsSender = Sender<Compat<TcpStream>>;
+type WsReceiver = Receiver<Compat<TcpStream>>;
+
+/// Kills the server when the benchmark ends.
+struct ServerProcess(Child);
+
+impl Drop for ServerProcess {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+async fn start_server() -> (ServerProcess, SocketAddr) {
+    let policy = std::env::temp_dir().join(format!("mantalon-bench-policy-{}.toml", std::process::id()));
+    std::fs::write(&policy, "allow_special_purpose_ips = true\n").expect("could not write the policy");
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").and_then(|listener| listener.local_addr()).expect("no free port");
+    let binary = std::env::var_os("MANTALON_BENCH_SERVER").unwrap_or_else(|| env!("CARGO_BIN_EXE_mantalon-server").into());
+    let child = Command::new(binary)
+        .args(["--port", &addr.port().to_string(), "--policy", policy.to_str().unwrap(), "--log", "error", "--usage-report-interval", "0"])
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("could not start the server");
+    let server = ServerProcess(child);
+    for _ in 0..100 {
+        if TcpStream::connect(addr).await.is_ok() {
+            return (server, addr);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the server did not start listening on {addr}");
+}
+
+async fn connect(server: SocketAddr, destination: SocketAddr) -> (WsSender, WsReceiver) {
+    let stream = TcpStream::connect(server).await.unwrap();
+    stream.set_nodelay(true).unwrap();
+    let path = format!("/mantalon-connect/ip4/{}/tcp/{}", destination.ip(), destination.port());
+    let host = server.to_string();
+    let mut client = Client::new(stream.compat(), &host, &path);
+    match client.handshake().await.unwrap() {
+        ServerResponse::Accepted { .. } => client.into_builder().finish(),
+        response => panic!("the server refused the relay: {response:?}"),
+    }
+}
+
+/// Sends `TRANSFER_SIZE` bytes in messages of `message_size` bytes, and returns once the destination received them all.
+async fn upload(server: SocketAddr, message_size: usize) -> Duration {
+    let sink = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let destination = sink.local_addr().unwrap();
+    let (done, received) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = sink.accept().await.unwrap();
+        let mut buffer = vec![0; 256 * 1024];
+        let mut total = 0;
+        while total < TRANSFER_SIZE {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => total += n,
+            }
+        }
+        let _ = done.send(total);
+    });
+
+    let (mut sender, _receiver) = connect(server, destination).await;
+    let message = vec![0x42; message_size];
+    let start = Instant::now();
+    for _ in 0..TRANSFER_SIZE / message_size {
+        sender.send_binary(&message).await.unwrap();
+    }
+    sender.flush().await.unwrap();
+    let total = received.await.unwrap();
+    assert_eq!(total, TRANSFER_SIZE, "the destination did not receive everything");
+    start.elapsed()
+}
+
+/// Receives `TRANSFER_SIZE` bytes the destination sends in writes of `write_size` bytes.
+async fn download(server: SocketAddr, write_size: usize) -> Duration {
+    let source = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let destination = source.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = source.accept().await.unwrap();
+        let data = vec![0x42; write_size];
+        for _ in 0..TRANSFER_SIZE / write_size {
+            if stream.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = stream.shutdown().await;
+    });
+
+    let (_sender, mut receiver) = connect(server, destination).await;
+    let mut message = Vec::new();
+    let mut total = 0;
+    let start = Instant::now();
+    while total < TRANSFER_SIZE {
+        message.clear();
+        match receiver.receive_data(&mut message).await {
+            Ok(_) => total += message.len(),
+            Err(e) => panic!("the relay failed after {total} bytes: {e}"),
+        }
+    }
+    start.elapsed()
+}
+
+fn report(name: &str, durations: Vec<Duration>) {
+    let best = durations.into_iter().min().unwrap();
+    let throughput = TRANSFER_SIZE as f64 / best.as_secs_f64() / (1024.0 * 1024.0);
+    println!("{name:<28} {throughput:>10.1} MiB/s ({best:.2?} for {} MiB)", TRANSFER_SIZE / (1024 * 1024));
+}
+
+#[tokio::main]
+async fn main() {
+    let (_server, addr) = start_server().await;
+    for message_size in [1024, 16 * 1024, 256 * 1024] {
+        let mut durations = Vec::new();
+        for _ in 0..RUNS {
+            durations.push(upload(addr, message_size).await);
+        }
+        report(&format!("upload, {} KiB messages", message_size / 1024), durations);
+    }
+    for write_size in [1024, 16 * 1024, 256 * 1024] {
+        let mut durations = Vec::new();
+        for _ in 0..RUNS {
+            durations.push(download(addr, write_size).await);
+        }
+        report(&format!("download, {} KiB writes", write_size / 1024), durations);
+    }
+}
@@ -0,0 +1,161 @@
+use http_body_util::{BodyExt, Limited};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::*;
+
+/// The largest request body the admin API accepts, as bodies only hold an address or a pattern.
+const MAX_BODY_SIZE: usize = 4096;
+
+#[derive(Serialize)]
+struct BanList {
+    clients: Vec<String>,
+    destinations: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct BanResult {
+    /// How many running relays the ban terminated.
+    terminated: usize,
+}
+
+fn text(status: StatusCode, body: impl Into<Bytes>) -> Response<FullBody> {
+    let mut response = Response::new(FullBody::from(body.into()));
+    *response.status_mut() = status;
+    response
+}
+
+fn json(value: &impl Serialize) -> Response<FullBody> {
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            let mut response = Response::new(FullBody::from(body));
+            response.headers_mut().insert("content-type", HeaderValue::from_static("application/json"));
+            response
+        }
+        Err(e) => text(StatusCode::INTERNAL_SERVER_ERROR, format!("Could not serialize response: {e}")),
+    }
+}
+
+/// Whether the request presents the admin token in an `Authorization: Bearer` header.
+fn authorized<B>(req: &Request<B>, token: &str) -> bool {
+    let presented = req.headers().get("authorization").and_then(|value| value.to_str().ok()).and_then(|value| value.strip_prefix("Bearer "));
+    let Some(presented) = presented.map(str::trim) else {
+        return false;
+    };
+    // Digests are compared in constant time, so that response times reveal neither how much of the token is right nor its length
+    let (presented, token) = (Sha256::digest(presented.as_bytes()), Sha256::digest(token.as_bytes()));
+    presented.iter().zip(token.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Reads a body holding a single value, such as an address to ban.
+async fn read_value(req: Request<Incoming>) -> Result<String, Response<FullBody>> {
+    let body = match Limited::new(req.into_body(), MAX_BODY_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return Err(text(StatusCode::BAD_REQUEST, format!("Could not read body: {e}"))),
+    };
+    match std::str::from_utf8(&body) {
+        Ok(value) if !value.trim().is_empty() => Ok(value.trim().to_owned()),
+        _ => Err(text(StatusCode::BAD_REQUEST, "Expected an address or a pattern in the body")),
+    }
+}
+
+pub async fn admin_handler(req: Request<Incoming>, state: &'static ServerState) -> Result<Response<FullBody>, BoxedError> {
+    let settings = state.settings();
+    if !settings.admin_token.as_deref().is_some_and(|token| authorized(&req, token)) {
+        let mut response = text(StatusCode::UNAUTHORIZED, "Missing or invalid admin token");
+        response.headers_mut().insert("www-authenticate", HeaderValue::from_static("Bearer"));
+        return Ok(response);
+    }
+
+    let (method, path) = (req.method().clone(), req.uri().path().trim_end_matches('/').to_owned());
+    let segments = path.split('/').skip(1).collect::<Vec<_>>();
+    let response = match (method, segments.as_slice()) {
+        (Method::GET, ["relays"]) => json(&state.relays.list()),
+        (Method::DELETE, ["relays", id]) => match id.parse().is_ok_and(|id| state.relays.kill(id)) {
+            true => {
+                info!("Terminating relay {id} through the admin API");
+                text(StatusCode::NO_CONTENT, "")
+            }
+            false => text(StatusCode::NOT_FOUND, format!("No running relay has id {id}")),
+        },
+        (Method::GET, ["bans"]) => json(&BanList { clients: state.bans.clients(), destinations: state.bans.destinations() }),
+        (method @ (Method::POST | Method::DELETE), ["bans", "clients"]) => {
+            let clients = match read_value(req).await.map(IpRule::try_from) {
+                Ok(Ok(clients)) => clients,
+                Ok(Err(e)) => return Ok(text(StatusCode::BAD_REQUEST, e)),
+                Err(response) => return Ok(response),
+            };
+            match method {
+                Method::POST => {
+                    state.bans.ban_client(clients.clone());
+                    let terminated = state.relays.kill_client(&clients);
+                    info!("Banned client {clients} through the admin API, terminating {terminated} relays");
+                    json(&BanResult { terminated })
+                }
+                _ if state.bans.unban_client(&clients) => {
+                    info!("Unbanned client {clients} through the admin API");
+                    text(StatusCode::NO_CONTENT, "")
+                }
+                _ => text(StatusCode::NOT_FOUND, format!("Client {clients} is not banned")),
+            }
+        }
+        (method @ (Method::POST | Method::DELETE), ["bans", "destinations"]) => {
+            let ban = match read_value(req).await.map(|value| value.parse::<DestinationBan>()) {
+                Ok(Ok(ban)) => ban,
+                Ok(Err(e)) => return Ok(text(StatusCode::BAD_REQUEST, e)),
+                Err(response) => return Ok(response),
+            };
+            match method {
+                Method::POST => {
+                    state.bans.ban_destination(ban.clone());
+                    let terminated = state.relays.kill_destination(&ban);
+                    info!("Banned destination {ban} through the admin API, terminating {terminated} relays");
+                    json(&BanResult { terminated })
+                }
+                _ if state.bans.unban_destination(&ban) => {
+                    info!("Unbanned destination {ban} through the admin API");
+                    text(StatusCode::NO_CONTENT, "")
+                }
+                _ => text(StatusCode::NOT_FOUND, format!("Destination {ban} is not banned")),
+            }
+        }
+        (_, ["relays" | "bans", ..]) => text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed"),
+        _ => text(StatusCode::NOT_FOUND, "Endpoint not found. Try /relays or /bans"),
+    };
+    Ok(response)
+}
+
+/// Serves the admin API on a listener until the server shuts down.
+pub async fn serve_admin(listener: Listener, state: &'static ServerState) {
+    loop {
+        let connection = tokio::select! {
+            connection = listener.accept() => connection,
+            () = state.shutdown.stopping() => return,
+        };
+        match connection {
+            Ok(Connection::Tcp(stream, addr)) => {
+                debug!("Accepting admin connection: {addr}");
+                tokio::spawn(serve_admin_connection(stream, state));
+            }
+            #[cfg(unix)]
+            Ok(Connection::Unix(stream)) => {
+                debug!("Accepting admin connection on unix socket");
+                tokio::spawn(serve_admin_connection(stream, state));
+            }
+            Err(e) => error!("Accepting new admin connection failed: {e}"),
+        }
+    }
+}
+
+async fn serve_admin_connection<IO>(stream: IO, state: &'static ServerState)
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let service = service_fn(move |r| admin_handler(r, state));
+    let conn = HttpBuilder::new()
+        .timer(TokioTimer::new())
+        .header_read_timeout(Duration::from_secs(state.settings().args.header_read_timeout))
+        .serve_connection(TokioIo::new(stream), service);
+    if let Err(e) = conn.await {
+        debug!("Admin connection failed: {e}");
+    }
+}
@@ -0,0 +1,262 @@
+use std::{collections::HashMap, fmt, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+use crate::*;
+
+/// A relay's client exceeded its daily quota.
+#[derive(Debug)]
+pub struct QuotaExceeded;
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "daily quota exceeded")
+    }
+}
+
+/// Limits a byte rate, letting the bytes through immediately but delaying the next ones.
+struct TokenBucket {
+    /// Bytes per second.
+    rate: f64,
+    /// Allowed bytes, which can go negative to represent a debt.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        TokenBucket { rate: rate as f64, tokens: rate as f64, last_refill: Instant::now() }
+    }
+
+    /// Takes `n` bytes from the bucket and returns how long to wait before sending more.
+    fn take(&mut self, n: usize) -> Duration {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * self.rate).min(self.rate);
+        self.last_refill = now;
+        self.tokens -= n as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+fn today() -> u64 {
+    now() / 86400
+}
+
+/// The usage of a client, identified by a [`LimitKey`].
+pub struct ClientUsage {
+    upload_bucket: Mutex<Option<TokenBucket>>,
+    download_bucket: Mutex<Option<TokenBucket>>,
+    /// The day `uploaded_today` and `downloaded_today` are about, in days since the unix epoch.
+    day: AtomicU64,
+    uploaded_today: AtomicU64,
+    downloaded_today: AtomicU64,
+}
+
+impl ClientUsage {
+    /// Resets the daily counters if the day changed.
+    fn roll_over(&self) {
+        let today = today();
+        if self.day.swap(today, Ordering::Relaxed) != today {
+            self.uploaded_today.store(0, Ordering::Relaxed);
+            self.downloaded_today.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn used_today(&self) -> u64 {
+        self.roll_over();
+        self.uploaded_today.load(Ordering::Relaxed) + self.downloaded_today.load(Ordering::Relaxed)
+    }
+}
+
+/// The rates and quota of a [`Bandwidth`], which can change while it is running.
+#[derive(Clone, Copy)]
+struct BandwidthLimits {
+    relay_upload_rate: Option<u64>,
+    relay_download_rate: Option<u64>,
+    client_upload_rate: Option<u64>,
+    client_download_rate: Option<u64>,
+    daily_quota: Option<u64>,
+}
+
+/// Bandwidth settings and the usage of every client.
+pub struct Bandwidth {
+    limits: Mutex<BandwidthLimits>,
+    clients: Mutex<HashMap<LimitKey, Arc<ClientUsage>>>,
+}
+
+/// Accounts for and shapes the traffic of a single relay.
+pub struct RelayMeter {
+    upload_bucket: Option<Mutex<TokenBucket>>,
+    download_bucket: Option<Mutex<TokenBucket>>,
+    client: Option<Arc<ClientUsage>>,
+    daily_quota: Option<u64>,
+    metrics: &'static Metrics,
+    last_activity: Mutex<Instant>,
+    pub uploaded: AtomicU64,
+    pub downloaded: AtomicU64,
+}
+
+impl Bandwidth {
+    pub fn new(relay_upload_rate: Option<u64>, relay_download_rate: Option<u64>, client_upload_rate: Option<u64>, client_download_rate: Option<u64>, daily_quota: Option<u64>) -> Bandwidth {
+        Bandwidth {
+            limits: Mutex::new(BandwidthLimits { relay_upload_rate, relay_download_rate, client_upload_rate, client_download_rate, daily_quota }),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Changes the rates and quota. Client rates apply to known clients immediately, relay rates to new relays only.
+    pub fn reconfigure(&self, relay_upload_rate: Option<u64>, relay_download_rate: Option<u64>, client_upload_rate: Option<u64>, client_download_rate: Option<u64>, daily_quota: Option<u64>) {
+        let mut limits = self.limits.lock().unwrap();
+        let clients = self.clients.lock().unwrap();
+        for client in clients.values() {
+            if limits.client_upload_rate != client_upload_rate {
+                *client.upload_bucket.lock().unwrap() = client_upload_rate.map(TokenBucket::new);
+            }
+            if limits.client_download_rate != client_download_rate {
+                *client.download_bucket.lock().unwrap() = client_download_rate.map(TokenBucket::new);
+            }
+        }
+        *limits = BandwidthLimits { relay_upload_rate, relay_download_rate, client_upload_rate, client_download_rate, daily_quota };
+    }
+
+    fn client(&self, key: &LimitKey) -> Arc<ClientUsage> {
+        let limits = *self.limits.lock().unwrap();
+        let mut clients = self.clients.lock().unwrap();
+        let client = clients.entry(key.clone()).or_insert_with(|| Arc::new(ClientUsage {
+            upload_bucket: Mutex::new(limits.client_upload_rate.map(TokenBucket::new)),
+            download_bucket: Mutex::new(limits.client_download_rate.map(TokenBucket::new)),
+            day: AtomicU64::new(today()),
+            uploaded_today: AtomicU64::new(0),
+            downloaded_today: AtomicU64::new(0),
+        }));
+        Arc::clone(client)
+    }
+
+    /// Checks whether a client can open a new relay.
+    pub fn check_quota(&self, key: Option<&LimitKey>) -> Result<(), QuotaExceeded> {
+        let (Some(key), Some(daily_quota)) = (key, self.limits.lock().unwrap().daily_quota) else {
+            return Ok(());
+        };
+        match self.client(key).used_today() >= daily_quota {
+            true => Err(QuotaExceeded),
+            false => Ok(()),
+        }
+    }
+
+    /// Creates the meter of a new relay, accounting its traffic to `key` and to the server-wide `metrics`.
+    pub fn meter(&self, key: Option<&LimitKey>, metrics: &'static Metrics) -> RelayMeter {
+        let limits = *self.limits.lock().unwrap();
+        RelayMeter {
+            upload_bucket: limits.relay_upload_rate.map(|rate| Mutex::new(TokenBucket::new(rate))),
+            download_bucket: limits.relay_download_rate.map(|rate| Mutex::new(TokenBucket::new(rate))),
+            client: key.map(|key| self.client(key)),
+            daily_quota: limits.daily_quota,
+            metrics,
+            last_activity: Mutex::new(Instant::now()),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the bytes uploaded and downloaded today by each client, biggest consumers first.
+    pub fn usage_report(&self) -> Vec<(LimitKey, u64, u64)> {
+        let clients = self.clients.lock().unwrap();
+        let mut report = clients.iter()
+            .map(|(key, client)| {
+                client.roll_over();
+                (key.clone(), client.uploaded_today.load(Ordering::Relaxed), client.downloaded_today.load(Ordering::Relaxed))
+            })
+            .filter(|(_, uploaded, downloaded)| uploaded + downloaded > 0)
+            .collect::<Vec<_>>();
+        report.sort_by_key(|(_, uploaded, downloaded)| std::cmp::Reverse(uploaded + downloaded));
+        report
+    }
+
+    /// Forgets the clients that have no active relay and did not use any bandwidth today.
+    pub fn cleanup(&self) {
+        self.clients.lock().unwrap().retain(|_, client| Arc::strong_count(client) > 1 || client.used_today() > 0);
+    }
+}
+
+impl RelayMeter {
+    /// Accounts for `n` bytes sent from the client to the destination, waiting if rate limits are exceeded.
+    pub async fn upload(&self, n: usize) -> Result<(), QuotaExceeded> {
+        self.uploaded.fetch_add(n as u64, Ordering::Relaxed);
+        self.metrics.uploaded(n);
+        let client_bucket = self.client.as_ref().map(|client| {
+            client.roll_over();
+            client.uploaded_today.fetch_add(n as u64, Ordering::Relaxed);
+            &client.upload_bucket
+        });
+        self.shape(n, self.upload_bucket.as_ref(), client_bucket).await
+    }
+
+    /// Accounts for `n` bytes sent from the destination to the client, waiting if rate limits are exceeded.
+    pub async fn download(&self, n: usize) -> Result<(), QuotaExceeded> {
+        self.downloaded.fetch_add(n as u64, Ordering::Relaxed);
+        self.metrics.downloaded(n);
+        let client_bucket = self.client.as_ref().map(|client| {
+            client.roll_over();
+            client.downloaded_today.fetch_add(n as u64, Ordering::Relaxed);
+            &client.download_bucket
+        });
+        self.shape(n, self.download_bucket.as_ref(), client_bucket).await
+    }
+
+    /// Resolves once no byte was relayed in either direction for `timeout`. Never resolves if `timeout` is zero.
+    pub async fn idle(&self, timeout: Duration) {
+        if timeout.is_zero() {
+            return std::future::pending().await;
+        }
+        loop {
+            let deadline = *self.last_activity.lock().unwrap() + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+
+    async fn shape(&self, n: usize, relay_bucket: Option<&Mutex<TokenBucket>>, client_bucket: Option<&Mutex<Option<TokenBucket>>>) -> Result<(), QuotaExceeded> {
+        *self.last_activity.lock().unwrap() = Instant::now();
+        if let (Some(client), Some(daily_quota)) = (&self.client, self.daily_quota) {
+            if client.used_today() >= daily_quota {
+                return Err(QuotaExceeded);
+            }
+        }
+
+        let relay_delay = relay_bucket.map(|bucket| bucket.lock().unwrap().take(n)).unwrap_or_default();
+        let client_delay = client_bucket.and_then(|bucket| bucket.lock().unwrap().as_mut().map(|bucket| bucket.take(n))).unwrap_or_default();
+        let delay = relay_delay.max(client_delay);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn quota_is_exhausted_once_reached() {
+        let metrics: &'static Metrics = Box::leak(Box::new(Metrics::new()));
+        let bandwidth = Bandwidth::new(None, None, None, None, Some(100));
+        let key = LimitKey::Ip(IpAddr::from([192, 0, 2, 1]));
+
+        let meter = bandwidth.meter(Some(&key), metrics);
+        assert!(meter.upload(60).await.is_ok());
+        assert!(meter.download(39).await.is_ok());
+        assert!(bandwidth.check_quota(Some(&key)).is_ok());
+
+        // Relays in progress stop at the byte that reaches the quota, and no new relay is opened
+        assert!(meter.upload(1).await.is_err());
+        assert!(bandwidth.check_quota(Some(&key)).is_err());
+        assert!(bandwidth.meter(Some(&key), metrics).download(1).await.is_err());
+
+        let other = LimitKey::Ip(IpAddr::from([192, 0, 2, 2]));
+        assert!(bandwidth.check_quota(Some(&other)).is_ok());
+        assert!(bandwidth.check_quota(None).is_ok());
+    }
+}
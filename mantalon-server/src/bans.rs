@@ -0,0 +1,107 @@
+use std::{collections::BTreeSet, fmt, str::FromStr, sync::RwLock};
+use crate::*;
+
+/// A destination banned through the admin API.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DestinationBan {
+    /// Addresses in a range, whichever domain they are reached through, as in `192.0.2.0/24`.
+    Addresses(IpRule),
+    /// Multiaddrs matching a pattern in which `*` stands for any sequence of characters, as in `/dns/*.example.com/tcp/*`.
+    Pattern(String),
+}
+
+impl FromStr for DestinationBan {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.starts_with('/') {
+            true => Ok(DestinationBan::Pattern(s.to_owned())),
+            false => IpRule::try_from(s.to_owned()).map(DestinationBan::Addresses),
+        }
+    }
+}
+
+impl fmt::Display for DestinationBan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DestinationBan::Addresses(range) => range.fmt(f),
+            DestinationBan::Pattern(pattern) => pattern.fmt(f),
+        }
+    }
+}
+
+impl DestinationBan {
+    /// Whether the ban applies to a destination, or to the address it is connected to.
+    pub fn matches(&self, addr: &Multiaddr, remote_ip: Option<IpAddr>) -> bool {
+        match self {
+            DestinationBan::Addresses(range) => {
+                let host_ip = match addr.iter().next() {
+                    Some(Protocol::Ip4(ip)) => Some(IpAddr::V4(ip)),
+                    Some(Protocol::Ip6(ip)) => Some(IpAddr::V6(ip)),
+                    _ => None,
+                };
+                host_ip.into_iter().chain(remote_ip).any(|ip| range.contains(ip))
+            }
+            DestinationBan::Pattern(pattern) => wildcard_match(pattern, &addr.to_string()),
+        }
+    }
+}
+
+/// Clients and destinations banned through the admin API, until the server restarts.
+#[derive(Default)]
+pub struct Bans {
+    clients: RwLock<BTreeSet<IpRule>>,
+    destinations: RwLock<BTreeSet<DestinationBan>>,
+}
+
+impl Bans {
+    /// Bans the clients in a range, returning whether they were not banned already.
+    pub fn ban_client(&self, clients: IpRule) -> bool {
+        self.clients.write().unwrap().insert(clients)
+    }
+
+    pub fn unban_client(&self, clients: &IpRule) -> bool {
+        self.clients.write().unwrap().remove(clients)
+    }
+
+    /// Bans a destination, returning whether it was not banned already.
+    pub fn ban_destination(&self, ban: DestinationBan) -> bool {
+        self.destinations.write().unwrap().insert(ban)
+    }
+
+    pub fn unban_destination(&self, ban: &DestinationBan) -> bool {
+        self.destinations.write().unwrap().remove(ban)
+    }
+
+    pub fn clients(&self) -> Vec<String> {
+        self.clients.read().unwrap().iter().map(ToString::to_string).collect()
+    }
+
+    pub fn destinations(&self) -> Vec<String> {
+        self.destinations.read().unwrap().iter().map(ToString::to_string).collect()
+    }
+
+    pub fn check_client(&self, client_ip: Option<IpAddr>) -> Result<(), MantalonError> {
+        let Some(ip) = client_ip else {
+            return Ok(());
+        };
+        match self.clients.read().unwrap().iter().find(|range| range.contains(ip)) {
+            Some(range) => Err(MantalonError::Banned(format!("client {ip} is banned by {range}"))),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks a destination, dropping the banned addresses among those it resolved to.
+    pub fn check_destination(&self, destination: &Destination, addrs: &mut Vec<SocketAddr>) -> Result<(), MantalonError> {
+        let bans = self.destinations.read().unwrap();
+        if let Some(ban) = bans.iter().find(|ban| ban.matches(&destination.addr, None)) {
+            return Err(MantalonError::Banned(format!("destination {destination} is banned by {ban}")));
+        }
+        let resolved = addrs.len();
+        addrs.retain(|addr| !bans.iter().any(|ban| ban.matches(&destination.addr, Some(addr.ip()))));
+        match (resolved, addrs.len()) {
+            (1.., 0) => Err(MantalonError::Banned(format!("all the addresses of {destination} are banned"))),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use std::{ffi::OsString, path::Path, sync::RwLock, time::SystemTime};
+use clap::{parser::ValueSource, CommandFactory, FromArgMatches};
+use crate::*;
+
+/// The command line merged with the configuration file it points to.
+///
+/// Keys of the file are the long names of the command line options, and options given on the command line take precedence.
+/// The `policy` and `egress` options can also be tables holding the rules themselves instead of paths to them.
+pub struct Config {
+    pub args: Args,
+    policy: Option<toml::Table>,
+    egress: Option<toml::Table>,
+}
+
+impl Config {
+    /// Parses the command line and the configuration file, exiting on invalid command lines as clap does.
+    pub fn parse(argv: &[OsString]) -> Result<Config, BoxedError> {
+        let matches = Args::command().get_matches_from(argv);
+        Config::from_matches(argv, matches)
+    }
+
+    /// Parses the command line and the configuration file, without exiting on errors.
+    pub fn try_parse(argv: &[OsString]) -> Result<Config, BoxedError> {
+        let matches = Args::command().try_get_matches_from(argv)?;
+        Config::from_matches(argv, matches)
+    }
+
+    fn from_matches(argv: &[OsString], matches: clap::ArgMatches) -> Result<Config, BoxedError> {
+        let Some(path) = matches.get_one::<PathBuf>("config") else {
+            return Ok(Config { args: Args::from_arg_matches(&matches)?, policy: None, egress: None });
+        };
+        let content = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+        let table: toml::Table = toml::from_str(&content).map_err(|e| format!("Invalid configuration file {}: {e}", path.display()))?;
+
+        // Turn the file into command line options that precede the actual ones
+        let command = Args::command();
+        let mut file_argv = vec![argv[0].clone()];
+        let (mut policy, mut egress) = (None, None);
+        for (key, value) in table {
+            let long = key.replace('_', "-");
+            let Some(arg) = command.get_arguments().find(|arg| arg.get_long() == Some(long.as_str()) && !matches!(long.as_str(), "config" | "check-config" | "issue-ticket")) else {
+                return Err(format!("Invalid configuration file {}: unknown option {key}", path.display()).into());
+            };
+            if matches.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine) {
+                continue;
+            }
+            let values = match value {
+                toml::Value::Table(table) if long == "policy" => {
+                    policy = Some(table);
+                    continue;
+                }
+                toml::Value::Table(table) if long == "egress" => {
+                    egress = Some(table);
+                    continue;
+                }
+                toml::Value::Boolean(true) => {
+                    file_argv.push(format!("--{long}").into());
+                    continue;
+                }
+                toml::Value::Boolean(false) => continue,
+                toml::Value::Array(values) => values,
+                value => vec![value],
+            };
+            for value in values {
+                let value = match value {
+                    toml::Value::String(value) => value,
+                    toml::Value::Integer(value) => value.to_string(),
+                    toml::Value::Float(value) => value.to_string(),
+                    _ => return Err(format!("Invalid configuration file {}: unsupported value for {key}", path.display()).into()),
+                };
+                file_argv.push(format!("--{long}={value}").into());
+            }
+        }
+        file_argv.extend(argv.iter().skip(1).cloned());
+
+        let args = Args::try_parse_from(file_argv).map_err(|e| {
+            let message = e.to_string();
+            let message = message.lines().next().unwrap_or_default().trim_start_matches("error: ");
+            format!("Invalid configuration file {}: {message}", path.display())
+        })?;
+        Ok(Config { args, policy, egress })
+    }
+}
+
+/// The settings that can change when the configuration is reloaded.
+///
+/// Relays keep the settings they were opened with.
+pub struct Settings {
+    pub args: Args,
+    pub policy: Policy,
+    pub egress: Egress,
+    pub ticket_key: Option<TicketKey>,
+    pub admin_token: Option<String>,
+    pub static_files: Option<StaticFiles>,
+}
+
+impl Settings {
+    /// Loads the files the configuration points to, returning the settings along with the DNS resolver they describe.
+    pub fn load(config: Config) -> Result<(Settings, Resolver), BoxedError> {
+        let Config { args, policy, egress } = config;
+        let policy = match (policy, &args.policy) {
+            (Some(table), _) => Policy::from_table(table)?,
+            (None, Some(path)) => Policy::load(path)?,
+            (None, None) => Policy::default(),
+        };
+        let egress = match (egress, &args.egress) {
+            (Some(table), _) => Egress::from_table(table)?,
+            (None, Some(path)) => Egress::load(path)?,
+            (None, None) => Egress::default(),
+        };
+        let ticket_key = match &args.ticket_key_file {
+            Some(path) => Some(TicketKey::load(path).map_err(|e| format!("Could not read ticket key: {e}"))?),
+            None => None,
+        };
+        let admin_token = match &args.admin_token_file {
+            Some(path) => {
+                let token = std::fs::read_to_string(path).map_err(|e| format!("Could not read admin token: {e}"))?;
+                match token.trim() {
+                    "" => return Err(format!("The admin token file {} is empty", path.display()).into()),
+                    token => Some(token.to_owned()),
+                }
+            }
+            None => None,
+        };
+        if let Some(root) = args.static_root.as_ref().filter(|root| !root.is_dir()) {
+            return Err(format!("Static root {} is not a directory", root.display()).into());
+        }
+        let static_files = args.static_root.clone().map(|root| StaticFiles::new(root, args.service_worker_allowed.clone()));
+        let resolver = Resolver::new(args.dns_upstreams.clone(), Duration::from_secs(args.dns_timeout), args.dns_tls_ca.as_deref()).map_err(|e| format!("Could not set up DNS resolvers: {e}"))?;
+        Ok((Settings { args, policy, egress, ticket_key, admin_token, static_files }, resolver))
+    }
+
+    /// The files the settings were loaded from, which trigger a reload when they change.
+    fn watched_files(&self) -> Vec<&Path> {
+        let args = &self.args;
+        [&args.config, &args.policy, &args.egress, &args.ticket_key_file, &args.admin_token_file].into_iter().flatten().map(PathBuf::as_path).collect()
+    }
+}
+
+impl Args {
+    pub fn dns_cache_config(&self) -> DnsCacheConfig {
+        DnsCacheConfig {
+            min_ttl: Duration::from_secs(self.dns_min_ttl),
+            max_ttl: Duration::from_secs(self.dns_max_ttl),
+            negative_ttl: Duration::from_secs(self.dns_negative_ttl),
+            default_ttl: Duration::from_secs(self.dns_default_ttl),
+            capacity: self.dns_cache_size,
+        }
+    }
+
+    pub fn keepalive(&self) -> Keepalive {
+        Keepalive { ping_interval: Duration::from_secs(self.ping_interval), pong_timeout: Duration::from_secs(self.pong_timeout) }
+    }
+}
+
+/// The settings shared by the server, replaced as a whole when reloading.
+pub struct CurrentSettings(RwLock<Arc<Settings>>);
+
+impl CurrentSettings {
+    pub fn new(settings: Settings) -> CurrentSettings {
+        CurrentSettings(RwLock::new(Arc::new(settings)))
+    }
+
+    pub fn get(&self) -> Arc<Settings> {
+        Arc::clone(&self.0.read().unwrap())
+    }
+}
+
+impl ServerState {
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.get()
+    }
+
+    /// Configures the components of the server for new relays.
+    pub fn apply(&self, settings: Settings, resolver: Resolver) {
+        let args = &settings.args;
+        set_log_filter(args.log.as_deref());
+        self.dns_cache.reconfigure(resolver, args.dns_cache_config());
+        self.limiter.reconfigure(args.relay_rate, args.relay_burst, args.max_relays_per_client, args.max_relays);
+        self.bandwidth.reconfigure(args.relay_upload_rate, args.relay_download_rate, args.client_upload_rate, args.client_download_rate, args.daily_quota);
+        *self.settings.0.write().unwrap() = Arc::new(settings);
+    }
+}
+
+/// Reads the configuration again and applies it to new relays, keeping the current one if it is invalid.
+fn reload(argv: &[OsString], state: &ServerState) -> Result<(), BoxedError> {
+    let (settings, resolver) = Settings::load(Config::try_parse(argv)?)?;
+    let (previous, current) = (&state.settings().args, &settings.args);
+    let restart_options = [
+        ("port", previous.port != current.port),
+        ("listen", previous.listen != current.listen),
+        ("tls_cert", previous.tls_cert != current.tls_cert),
+        ("tls_key", previous.tls_key != current.tls_key),
+        ("config_poll_interval", previous.config_poll_interval != current.config_poll_interval),
+        ("admin_listen", previous.admin_listen != current.admin_listen),
+    ];
+    for (option, _) in restart_options.into_iter().filter(|(_, changed)| *changed) {
+        warn!("The {option} option changed, which only takes effect after a restart");
+    }
+    state.apply(settings, resolver);
+    Ok(())
+}
+
+fn modification_times(settings: &Settings) -> Vec<Option<SystemTime>> {
+    settings.watched_files().into_iter().map(|path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()).collect()
+}
+
+/// Reloads the configuration when the process receives SIGHUP, and when one of its files changes.
+pub fn reload_config_on_change(argv: Vec<OsString>, state: &'static ServerState) -> std::io::Result<()> {
+    #[cfg(unix)]
+    let mut hangups = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    let poll_interval = Duration::from_secs(state.settings().args.config_poll_interval);
+
+    tokio::spawn(async move {
+        let mut modified = modification_times(&state.settings());
+        loop {
+            let hangup = async {
+                #[cfg(unix)]
+                hangups.recv().await;
+                #[cfg(not(unix))]
+                std::future::pending::<()>().await;
+            };
+            let poll = async {
+                match poll_interval.is_zero() {
+                    true => std::future::pending().await,
+                    false => tokio::time::sleep(poll_interval).await,
+                }
+            };
+            tokio::select! {
+                () = hangup => (),
+                () = poll => {
+                    if modification_times(&state.settings()) == modified {
+                        continue;
+                    }
+                }
+            }
+
+            match reload(&argv, state) {
+                Ok(()) => info!("Reloaded configuration"),
+                Err(e) => error!("Could not reload configuration, keeping the previous one: {e}"),
+            }
+            modified = modification_times(&state.settings());
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,190 @@
+use std::{collections::VecDeque, fmt};
+use crate::*;
+
+/// How many TXT lookups resolving a `/dnsaddr` can take, including nested ones.
+const MAX_DNSADDR_LOOKUPS: usize = 8;
+
+/// How many destinations a `/dnsaddr` can resolve to.
+const MAX_DNSADDR_DESTINATIONS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn matches(self, ip: &IpAddr) -> bool {
+        match self {
+            Family::V4 => ip.is_ipv4(),
+            Family::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Host {
+    Ip(IpAddr),
+    /// A domain from `/dns`, or from `/dns4` and `/dns6` which only keep addresses of their family.
+    Domain(String, Option<Family>),
+}
+
+/// A host and a transport a relay can connect to, as in `/dns4/example.com/tcp/443`.
+#[derive(Debug, Clone)]
+pub struct Destination {
+    pub addr: Multiaddr,
+    pub host: Host,
+    pub udp: bool,
+    pub port: u16,
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.addr.fmt(f)
+    }
+}
+
+impl Destination {
+    pub fn parse(addr: &Multiaddr) -> Result<Destination, MantalonError> {
+        // Extract the host from the multiaddr
+        let mut protocols = addr.iter();
+        let host = match protocols.next() {
+            Some(Protocol::Ip4(ip)) => Host::Ip(IpAddr::V4(ip)),
+            Some(Protocol::Ip6(ip)) => Host::Ip(IpAddr::V6(ip)),
+            Some(Protocol::Dns(domain)) => Host::Domain(domain.into_owned(), None),
+            Some(Protocol::Dns4(domain)) => Host::Domain(domain.into_owned(), Some(Family::V4)),
+            Some(Protocol::Dns6(domain)) => Host::Domain(domain.into_owned(), Some(Family::V6)),
+            // Onion services carry their port, and can only be reached through a proxy such as Tor
+            Some(Protocol::Onion3(onion)) => {
+                let port = onion.port();
+                let text = Protocol::Onion3(onion).to_string(); // As in /onion3/<base32 service>:<port>
+                let service = text.trim_start_matches("/onion3/").rsplit_once(':').map(|(service, _)| service).unwrap_or_default();
+                if let Some(p) = protocols.next() {
+                    return Err(MantalonError::UnsupportedProtocol(p.to_string()));
+                }
+                return Ok(Destination { addr: addr.clone(), host: Host::Domain(format!("{service}.onion"), None), udp: false, port });
+            }
+            Some(p) => return Err(MantalonError::UnsupportedProtocol(p.to_string())),
+            None => return Err(MantalonError::MissingProtocol),
+        };
+
+        // Extract the transport protocol and port from the multiaddr
+        let (udp, port) = match protocols.next() {
+            Some(Protocol::Tcp(port)) => (false, port),
+            Some(Protocol::Udp(port)) => (true, port),
+            Some(p) => return Err(MantalonError::UnsupportedProtocol(p.to_string())),
+            None => return Err(MantalonError::MissingProtocol),
+        };
+
+        // Ensure there are no more protocols
+        if let Some(p) = protocols.next() {
+            return Err(MantalonError::UnsupportedProtocol(p.to_string()));
+        }
+
+        Ok(Destination { addr: addr.clone(), host, udp, port })
+    }
+
+    pub fn domain(&self) -> Option<&str> {
+        match &self.host {
+            Host::Ip(_) => None,
+            Host::Domain(domain, _) => Some(domain),
+        }
+    }
+
+    /// Checks the parts of the destination known before resolution against the policy, returning whether its domain is explicitly allowed.
+    fn check_policy(&self, policy: &Policy) -> Result<bool, MantalonError> {
+        policy.check_destination(self.domain(), self.port).map_err(|violation| {
+            info!("Refused destination {self}: {violation}");
+            MantalonError::PolicyDenied(violation)
+        })
+    }
+
+    /// Checks a destination connected to through an egress proxy, which resolves domains itself.
+    pub fn check_unresolved(&self, policy: &Policy) -> Result<(), MantalonError> {
+        let domain_allowed = self.check_policy(policy)?;
+        let result = match &self.host {
+            Host::Ip(ip) => policy.filter_ips(&mut vec![*ip], domain_allowed),
+            Host::Domain(domain, _) => policy.check_unresolved_domain(domain, domain_allowed),
+        };
+        result.map_err(|violation| {
+            info!("Refused destination {self}: {violation}");
+            MantalonError::PolicyDenied(violation)
+        })
+    }
+
+    /// Checks the destination against the policy and resolves it into the addresses that can be connected to.
+    pub async fn resolve(&self, policy: &Policy, state: &'static ServerState) -> Result<Vec<SocketAddr>, MantalonError> {
+        // Check the destination against the policy before doing any network operation
+        let domain_allowed = self.check_policy(policy)?;
+
+        // Resolve the domain
+        let mut ips = match &self.host {
+            Host::Ip(ip) => vec![*ip],
+            Host::Domain(domain, _) if normalize_domain(domain).ends_with(".onion") => {
+                return Err(MantalonError::UnsupportedProtocol(format!("{self} can only be reached through an egress proxy such as Tor")));
+            }
+            Host::Domain(domain, family) => {
+                let mut ips = state.dns_cache.resolve(domain, &state.metrics).await;
+                if let Some(family) = family {
+                    ips.retain(|ip| family.matches(ip));
+                }
+                if ips.is_empty() {
+                    return Err(MantalonError::DnsFailed(domain.to_string()));
+                }
+                ips
+            }
+        };
+
+        // Only keep the addresses the policy allows. We connect to these exact addresses so DNS rebinding cannot bypass the checks.
+        if let Err(violation) = policy.filter_ips(&mut ips, domain_allowed) {
+            info!("Refused destination {self}: {violation}");
+            return Err(MantalonError::PolicyDenied(violation));
+        }
+        Ok(ips.into_iter().map(|ip| SocketAddr::new(ip, self.port)).collect())
+    }
+}
+
+/// Resolves `/dnsaddr/<domain>` into the destinations listed in the `dnsaddr=<multiaddr>` TXT records of `_dnsaddr.<domain>`.
+///
+/// As in libp2p, only the multiaddrs ending with `suffix`, what follows `/dnsaddr/<domain>` in the original multiaddr, are kept.
+/// Those starting with `/dnsaddr` are resolved in turn.
+pub async fn resolve_dnsaddr(domain: &str, suffix: &Multiaddr, dns_cache: &DnsCache) -> Vec<Destination> {
+    let mut pending = VecDeque::from([(domain.to_owned(), suffix.clone())]);
+    let mut destinations = Vec::new();
+    let mut lookups = 0;
+    while let Some((domain, suffix)) = pending.pop_front() {
+        if lookups == MAX_DNSADDR_LOOKUPS {
+            warn!("Gave up resolving /dnsaddr/{domain} after {lookups} lookups");
+            break;
+        }
+        lookups += 1;
+
+        let Some(records) = dns_cache.resolve_txt(&format!("_dnsaddr.{domain}")).await else {
+            continue;
+        };
+        for record in records {
+            let Some(addr) = record.strip_prefix("dnsaddr=") else {
+                continue;
+            };
+            let addr = match addr.parse::<Multiaddr>() {
+                Ok(addr) => addr,
+                Err(e) => {
+                    debug!("Ignoring invalid multiaddr {addr} of /dnsaddr/{domain}: {e}");
+                    continue;
+                }
+            };
+            if !addr.ends_with(&suffix) {
+                continue;
+            }
+            match addr.iter().next() {
+                Some(Protocol::Dnsaddr(nested)) => pending.push_back((nested.into_owned(), addr.iter().skip(1).collect())),
+                _ => match Destination::parse(&addr) {
+                    Ok(destination) if destinations.len() < MAX_DNSADDR_DESTINATIONS => destinations.push(destination),
+                    Ok(_) => (),
+                    Err(e) => debug!("Ignoring {addr} of /dnsaddr/{domain}: {e}"),
+                },
+            }
+        }
+    }
+    destinations
+}
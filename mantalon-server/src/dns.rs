@@ -1,104 +1,305 @@
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
-use tokio::sync::RwLock;
-use log::*;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use futures::future::{BoxFuture, FutureExt, Shared};
+use crate::{Metrics, Resolver};
 
-pub type DnsCache = Arc<RwLock<HashMap<String, (u64, Vec<IpAddr>)>>>;
-
-fn now() -> u64 {
+pub fn now() -> u64 {
     std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
 }
 
-#[cfg(feature = "custom_dns")]
-pub async fn resolve(cache: DnsCache, domain: &str, dns_provider: SocketAddr) -> Vec<IpAddr> {
-    use trust_dns_client::client::{AsyncClient, ClientHandle};
-    use trust_dns_client::rr::{DNSClass, Name, RData, RecordType};
-    use trust_dns_client::tcp::TcpClientStream;
-    use trust_dns_client::proto::iocompat::AsyncIoTokioAsStd;
-    use futures::future::join_all;
-    use tokio::net::TcpStream;
-    use std::str::FromStr;
-
-    // Check cache
-    if let Some((ttl, ips)) = cache.read().await.get(domain) {
-        if now() <= *ttl && !ips.is_empty() {
-            return ips.clone();
+/// The answer to a lookup, which is cached even when empty (negative caching).
+pub struct Lookup {
+    pub ips: Vec<IpAddr>,
+    /// The smallest TTL of the records, if the resolver provides them.
+    pub ttl: Option<Duration>,
+}
+
+struct CacheEntry {
+    ips: Vec<IpAddr>,
+    expiry: Instant,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    /// Domains by the tick they were last used at, the least recently used first.
+    by_use: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// How answers are cached.
+pub struct DnsCacheConfig {
+    pub min_ttl: Duration,
+    pub max_ttl: Duration,
+    pub negative_ttl: Duration,
+    /// How long to cache answers when the resolver does not tell their TTL, before clamping.
+    pub default_ttl: Duration,
+    pub capacity: usize,
+}
+
+/// A bounded LRU cache of DNS answers that deduplicates concurrent lookups of the same domain.
+pub struct DnsCache {
+    resolver: RwLock<Arc<Resolver>>,
+    config: RwLock<DnsCacheConfig>,
+    state: Mutex<CacheState>,
+    in_flight: Mutex<HashMap<String, Shared<BoxFuture<'static, Vec<IpAddr>>>>>,
+}
+
+impl DnsCache {
+    pub fn new(resolver: Resolver, config: DnsCacheConfig) -> DnsCache {
+        DnsCache {
+            resolver: RwLock::new(Arc::new(resolver)),
+            config: RwLock::new(config),
+            state: Mutex::new(CacheState::default()),
+            in_flight: Mutex::new(HashMap::new()),
         }
     }
 
-    // Create client
-    let (stream, sender) = TcpClientStream::<AsyncIoTokioAsStd<TcpStream>>::new(dns_provider);
-    let client = AsyncClient::new(stream, sender, None);
-    let Ok((mut client, bg)) = client.await else {
-        error!("Failed to connect to DNS provider");
-        return Vec::new();
-    };
-    tokio::spawn(bg);
-
-    // Build queries
-    let mut queries = Vec::new();
-    let Ok(name) = Name::from_str(domain) else {
-        error!("Invalid domain name: {domain}");
-        return Vec::new();
-    };
-    let aaaa_query = client.query(name.clone(), DNSClass::IN, RecordType::AAAA);
-    queries.push(aaaa_query);
-    let a_query = client.query(name, DNSClass::IN, RecordType::A);
-    queries.push(a_query);
-
-    // Read results
-    let results = join_all(queries).await;
-    let mut ips = Vec::new();
-    for resp in results.into_iter().filter_map(|res| res.ok()) {
-        for answer in resp.answers() {
-            match answer.data() {
-                Some(RData::A(ip)) => ips.push(IpAddr::V4(ip.0)),
-                Some(RData::AAAA(ip)) => ips.push(IpAddr::V6(ip.0)),
-                _ => {}
-            }
-        }
+    /// Replaces the resolver used for the next lookups and the way their answers are cached.
+    ///
+    /// Answers already cached are kept until they expire or get evicted.
+    pub fn reconfigure(&self, resolver: Resolver, config: DnsCacheConfig) {
+        *self.resolver.write().unwrap() = Arc::new(resolver);
+        *self.config.write().unwrap() = config;
     }
 
-    // Cache results
-    let ttl = now() + 5*60;
-    cache.write().await.insert(domain.to_owned(), (ttl, ips.clone()));
+    fn resolver(&self) -> Arc<Resolver> {
+        Arc::clone(&self.resolver.read().unwrap())
+    }
 
-    ips
-}
+    fn get(&self, domain: &str) -> Option<Vec<IpAddr>> {
+        let mut state = self.state.lock().unwrap();
+        let CacheState { entries, by_use, tick } = &mut *state;
+        let entry = entries.get_mut(domain)?;
+        by_use.remove(&entry.last_used);
+        if entry.expiry <= Instant::now() {
+            entries.remove(domain);
+            return None;
+        }
+        *tick += 1;
+        entry.last_used = *tick;
+        by_use.insert(*tick, domain.to_owned());
+        Some(entry.ips.clone())
+    }
 
-#[cfg(not(feature = "custom_dns"))]
-pub async fn resolve(cache: DnsCache, domain: &str, _dns_provider: SocketAddr) -> Vec<IpAddr> {
-    use std::{net::{SocketAddr, ToSocketAddrs}, thread, io::Result as IoResult, vec::IntoIter};
-    use tokio::sync::oneshot;
+    fn insert(&self, domain: String, lookup: &Lookup) {
+        let config = self.config.read().unwrap();
+        let ttl = match lookup.ips.is_empty() {
+            true => config.negative_ttl,
+            false => lookup.ttl.unwrap_or(config.default_ttl).clamp(config.min_ttl, config.max_ttl.max(config.min_ttl)),
+        };
+        let capacity = config.capacity;
+        drop(config);
+        if capacity == 0 || ttl.is_zero() {
+            return;
+        }
 
-    // Check cache
-    if let Some((ttl, ips)) = cache.read().await.get(domain) {
-        if now() <= *ttl && !ips.is_empty() {
-            return ips.clone();
+        let mut state = self.state.lock().unwrap();
+        let CacheState { entries, by_use, tick } = &mut *state;
+        if let Some(previous) = entries.remove(&domain) {
+            by_use.remove(&previous.last_used);
+        }
+        while entries.len() >= capacity {
+            let Some((_, evicted)) = by_use.pop_first() else { break };
+            entries.remove(&evicted);
         }
+        *tick += 1;
+        by_use.insert(*tick, domain.clone());
+        entries.insert(domain, CacheEntry { ips: lookup.ips.clone(), expiry: Instant::now() + ttl, last_used: *tick });
     }
 
-    // Resolve domain in another thread
-    let (sender, receiver) = oneshot::channel::<IoResult<IntoIter<SocketAddr>>>();
-    let socket_addr = format!("{domain}:0");
-    thread::spawn(move || {
-        let result = socket_addr.to_socket_addrs();
-        let _ = sender.send(result);
-    });
-
-    // Wait and process the results
-    let result = receiver.await.expect("The resolver thread should not drop");
-    match result {
-        Ok(addrs) => {
-            let ips = addrs.map(|addr| addr.ip()).collect::<Vec<IpAddr>>();
-            let hypothetical_ttl = now() + 5*60;
-            cache.write().await.insert(domain.to_owned(), (hypothetical_ttl, ips.clone()));
-            ips
+    /// Resolves a domain, from the cache if possible.
+    ///
+    /// Concurrent calls for a domain missing from the cache share a single lookup, which runs in its own task.
+    /// Returns an empty list if the domain could not be resolved.
+    pub async fn resolve(&'static self, domain: &str, metrics: &'static Metrics) -> Vec<IpAddr> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(ips) = self.get(&domain) {
+            metrics.dns_cache_hit();
+            return ips;
         }
-        Err(e) => {
-            error!("Failed to resolve domain {domain}: {e}");
-            Vec::new()
+
+        let shared_lookup = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&domain) {
+                Some(shared_lookup) => shared_lookup.clone(),
+                None => {
+                    let domain2 = domain.clone();
+                    let resolver = self.resolver();
+                    // Spawned so that the lookup completes and leaves `in_flight` even if every caller gives up on it
+                    let lookup = tokio::spawn(async move {
+                        let start = Instant::now();
+                        let result = resolver.lookup(&domain2).await;
+                        metrics.dns_resolved(start.elapsed());
+                        if let Some(result) = &result {
+                            self.insert(domain2.clone(), result);
+                        }
+                        self.in_flight.lock().unwrap().remove(&domain2);
+                        result.map(|result| result.ips).unwrap_or_default()
+                    });
+                    let shared_lookup = lookup.map(|ips| ips.unwrap_or_default()).boxed().shared();
+                    in_flight.insert(domain, shared_lookup.clone());
+                    shared_lookup
+                }
+            }
+        };
+        shared_lookup.await
+    }
+
+    /// Resolves the TXT records of a name, bypassing the cache.
+    pub async fn resolve_txt(&self, name: &str) -> Option<Vec<String>> {
+        self.resolver().lookup_txt(name).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use hickory_proto::{op::{Message, MessageType}, rr::{rdata::A, RData, Record, RecordType}};
+    use tokio::net::UdpSocket;
+    use crate::DnsUpstream;
+    use super::*;
+
+    const DOMAIN: &str = "example.com";
+
+    /// A DNS server on 127.0.0.1 that answers A queries with 192.0.2.1 after `delay`, and counts the queries it gets.
+    async fn stub(delay: Duration) -> (Resolver, Arc<AtomicUsize>) {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&queries);
+        tokio::spawn(async move {
+            let mut buffer = vec![0; 4096];
+            while let Ok((n, peer)) = socket.recv_from(&mut buffer).await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let request = Message::from_vec(&buffer[..n]).unwrap();
+                let socket = Arc::clone(&socket);
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let mut response = Message::new();
+                    response.set_id(request.id()).set_message_type(MessageType::Response).add_queries(request.queries().to_vec());
+                    let query = &request.queries()[0];
+                    if query.query_type() == RecordType::A {
+                        response.add_answer(Record::from_rdata(query.name().clone(), 300, RData::A(A::new(192, 0, 2, 1))));
+                    }
+                    socket.send_to(&response.to_vec().unwrap(), peer).await.unwrap();
+                });
+            }
+        });
+        let resolver = Resolver::new(vec![DnsUpstream::Udp(addr)], Duration::from_secs(5), None).unwrap();
+        (resolver, queries)
+    }
+
+    fn config(capacity: usize) -> DnsCacheConfig {
+        DnsCacheConfig {
+            min_ttl: Duration::from_secs(60),
+            max_ttl: Duration::from_secs(600),
+            negative_ttl: Duration::from_secs(5),
+            default_ttl: Duration::from_secs(300),
+            capacity,
         }
     }
+
+    fn cache(resolver: Resolver, config: DnsCacheConfig) -> &'static DnsCache {
+        Box::leak(Box::new(DnsCache::new(resolver, config)))
+    }
+
+    fn metrics() -> &'static Metrics {
+        Box::leak(Box::new(Metrics::new()))
+    }
+
+    fn lookup(ttl: Option<u64>) -> Lookup {
+        Lookup { ips: vec![IpAddr::from([192, 0, 2, 1])], ttl: ttl.map(Duration::from_secs) }
+    }
+
+    /// Returns the cached domains, without counting it as a use.
+    fn cached(cache: &DnsCache) -> Vec<String> {
+        let mut domains: Vec<String> = cache.state.lock().unwrap().entries.keys().cloned().collect();
+        domains.sort();
+        domains
+    }
+
+    /// Returns the time left before the answer for `domain` expires.
+    fn ttl(cache: &DnsCache, domain: &str) -> Option<Duration> {
+        let state = cache.state.lock().unwrap();
+        state.entries.get(domain).map(|entry| entry.expiry - Instant::now())
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_share_a_lookup() {
+        let (resolver, queries) = stub(Duration::from_millis(200)).await;
+        let (cache, metrics) = (cache(resolver, config(16)), metrics());
+
+        let answers = futures::future::join_all((0..10).map(|_| cache.resolve("Example.COM.", metrics))).await;
+        assert!(answers.iter().all(|ips| ips == &[IpAddr::from([192, 0, 2, 1])]));
+        // One lookup queries both A and AAAA records
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+
+        assert_eq!(cache.resolve(DOMAIN, metrics).await, vec![IpAddr::from([192, 0, 2, 1])]);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lookups_complete_without_waiters() {
+        let (resolver, queries) = stub(Duration::from_millis(200)).await;
+        let (cache, metrics) = (cache(resolver, config(16)), metrics());
+
+        assert!(tokio::time::timeout(Duration::from_millis(50), cache.resolve(DOMAIN, metrics)).await.is_err());
+        assert!(cache.in_flight.lock().unwrap().contains_key(DOMAIN));
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(cache.in_flight.lock().unwrap().is_empty());
+        assert_eq!(cache.resolve(DOMAIN, metrics).await, vec![IpAddr::from([192, 0, 2, 1])]);
+        assert_eq!(queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn least_recently_used_answers_are_evicted() {
+        let (resolver, _) = stub(Duration::ZERO).await;
+        let cache = cache(resolver, config(2));
+
+        cache.insert(String::from("a"), &lookup(None));
+        cache.insert(String::from("b"), &lookup(None));
+        assert!(cache.get("a").is_some());
+        cache.insert(String::from("c"), &lookup(None));
+        assert_eq!(cached(cache), ["a", "c"]);
+
+        // Replacing an answer counts as a use
+        cache.insert(String::from("a"), &lookup(None));
+        cache.insert(String::from("b"), &lookup(None));
+        assert_eq!(cached(cache), ["a", "b"]);
+
+        let (resolver, _) = stub(Duration::ZERO).await;
+        let disabled = self::cache(resolver, config(0));
+        disabled.insert(String::from("a"), &lookup(None));
+        assert!(cached(disabled).is_empty());
+    }
+
+    #[tokio::test]
+    async fn ttls_are_clamped() {
+        let (resolver, _) = stub(Duration::ZERO).await;
+        let cache = cache(resolver, config(16));
+        let close_to = |domain, secs| ttl(cache, domain).is_some_and(|ttl| ttl <= Duration::from_secs(secs) && ttl > Duration::from_secs(secs - 5));
+
+        cache.insert(String::from("short"), &lookup(Some(1)));
+        assert!(close_to("short", 60));
+        cache.insert(String::from("long"), &lookup(Some(86400)));
+        assert!(close_to("long", 600));
+        cache.insert(String::from("within"), &lookup(Some(120)));
+        assert!(close_to("within", 120));
+        cache.insert(String::from("unknown"), &lookup(None));
+        assert!(close_to("unknown", 300));
+        cache.insert(String::from("missing"), &Lookup { ips: Vec::new(), ttl: Some(Duration::from_secs(3600)) });
+        assert!(close_to("missing", 5));
+        assert_eq!(cache.get("missing"), Some(Vec::new()));
+
+        let (resolver, _) = stub(Duration::ZERO).await;
+        cache.reconfigure(resolver, DnsCacheConfig { negative_ttl: Duration::ZERO, ..config(16) });
+        cache.insert(String::from("uncached"), &Lookup { ips: Vec::new(), ttl: None });
+        assert!(ttl(cache, "uncached").is_none());
+    }
 }
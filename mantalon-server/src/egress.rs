@@ -0,0 +1,266 @@
+use std::{collections::HashMap, fmt, io::{Error as IoError, ErrorKind}, path::Path, str::FromStr};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::Uri;
+use serde::Deserialize;
+use crate::*;
+
+/// A proxy through which TCP connections to destinations leave the server.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Proxy {
+    /// A SOCKS5 proxy, such as Tor, to which domains are passed unresolved.
+    Socks5 { proxy: String, credentials: Option<(String, String)> },
+    /// An HTTP proxy supporting the `CONNECT` method, along with the value of its `Proxy-Authorization` header.
+    HttpConnect { proxy: String, authorization: Option<String> },
+}
+
+impl FromStr for Proxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri: Uri = s.parse().map_err(|e| format!("invalid upstream {s}: {e}"))?;
+        let authority = uri.authority().ok_or_else(|| format!("missing proxy address in {s}"))?;
+        let credentials = authority.as_str().rsplit_once('@').map(|(userinfo, _)| match userinfo.split_once(':') {
+            Some((username, password)) => (username.to_owned(), password.to_owned()),
+            None => (userinfo.to_owned(), String::new()),
+        });
+        let proxy = |default_port| format!("{}:{}", authority.host(), authority.port_u16().unwrap_or(default_port));
+        match uri.scheme_str() {
+            Some("socks5" | "socks5h") => Ok(Proxy::Socks5 { proxy: proxy(1080), credentials }),
+            Some("http") => Ok(Proxy::HttpConnect {
+                proxy: proxy(80),
+                authorization: credentials.map(|(username, password)| format!("Basic {}", STANDARD.encode(format!("{username}:{password}")))),
+            }),
+            _ => Err(format!("unsupported upstream {s}, expected socks5://host:port or http://host:port")),
+        }
+    }
+}
+
+impl TryFrom<String> for Proxy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Proxy::Socks5 { proxy, .. } => write!(f, "socks5://{proxy}"),
+            Proxy::HttpConnect { proxy, .. } => write!(f, "http://{proxy}"),
+        }
+    }
+}
+
+impl Proxy {
+    /// Opens a TCP connection to `host` through the proxy, which resolves it if it is a domain.
+    pub async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, IoError> {
+        match self {
+            Proxy::Socks5 { proxy, credentials } => {
+                let mut stream = TcpStream::connect(proxy.as_str()).await?;
+                socks5_handshake(&mut stream, host, port, credentials.as_ref()).await?;
+                Ok(stream)
+            }
+            Proxy::HttpConnect { proxy, authorization } => {
+                let mut stream = TcpStream::connect(proxy.as_str()).await?;
+                http_connect_handshake(&mut stream, host, port, authorization.as_deref()).await?;
+                Ok(stream)
+            }
+        }
+    }
+}
+
+/// Asks a SOCKS5 proxy to connect to a destination (RFC 1928), authenticating with a username and password if needed (RFC 1929).
+async fn socks5_handshake(stream: &mut TcpStream, host: &str, port: u16, credentials: Option<&(String, String)>) -> Result<(), IoError> {
+    let protocol_error = |message: &str| IoError::new(ErrorKind::InvalidData, format!("SOCKS5 proxy {message}"));
+
+    // Negotiate the authentication method
+    let method = match credentials {
+        Some(_) => 2,
+        None => 0,
+    };
+    stream.write_all(&[5, 1, method]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [5, method] {
+        return Err(protocol_error("refused the authentication method"));
+    }
+    if let Some((username, password)) = credentials {
+        let (username, password) = (username.as_bytes(), password.as_bytes());
+        if username.len() > 255 || password.len() > 255 {
+            return Err(IoError::new(ErrorKind::InvalidInput, "SOCKS5 credentials are too long"));
+        }
+        stream.write_all(&[&[1, username.len() as u8], username, &[password.len() as u8], password].concat()).await?;
+        stream.read_exact(&mut reply).await?;
+        if reply[1] != 0 {
+            return Err(IoError::new(ErrorKind::PermissionDenied, "SOCKS5 proxy refused the credentials"));
+        }
+    }
+
+    // Ask for the connection
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => request.extend([&[1][..], &ip.octets()].concat()),
+        Ok(IpAddr::V6(ip)) => request.extend([&[4][..], &ip.octets()].concat()),
+        Err(_) if host.len() <= 255 => request.extend([&[3, host.len() as u8][..], host.as_bytes()].concat()),
+        Err(_) => return Err(IoError::new(ErrorKind::InvalidInput, "domain too long for SOCKS5")),
+    }
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    // Read the reply, including the address the proxy bound, which we don't need
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    let kind = match reply[1] {
+        0 => None,
+        2 => Some((ErrorKind::PermissionDenied, "connection not allowed by ruleset")),
+        3 => Some((ErrorKind::NetworkUnreachable, "network unreachable")),
+        4 => Some((ErrorKind::HostUnreachable, "host unreachable")),
+        5 => Some((ErrorKind::ConnectionRefused, "connection refused")),
+        6 => Some((ErrorKind::TimedOut, "TTL expired")),
+        _ => Some((ErrorKind::Other, "general failure")),
+    };
+    if let Some((kind, message)) = kind {
+        return Err(IoError::new(kind, format!("SOCKS5 proxy could not connect: {message}")));
+    }
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        _ => return Err(protocol_error("sent an invalid reply")),
+    };
+    let mut bound = vec![0; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Asks an HTTP proxy to connect to a destination with the `CONNECT` method.
+async fn http_connect_handshake(stream: &mut TcpStream, host: &str, port: u16, authorization: Option<&str>) -> Result<(), IoError> {
+    let authority = match host.parse::<Ipv6Addr>() {
+        Ok(_) => format!("[{host}]:{port}"),
+        Err(_) => format!("{host}:{port}"),
+    };
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(authorization) = authorization {
+        request.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read the response head byte by byte, so as not to consume any byte of the tunnel
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() == 16 * 1024 {
+            return Err(IoError::new(ErrorKind::InvalidData, "HTTP proxy response too large"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    let status_line = String::from_utf8_lossy(&head).lines().next().unwrap_or_default().to_owned();
+    let kind = match status_line.split(' ').nth(1).and_then(|status| status.parse::<u16>().ok()) {
+        Some(200..=299) => return Ok(()),
+        Some(403 | 407) => ErrorKind::PermissionDenied,
+        Some(502 | 503) => ErrorKind::ConnectionRefused,
+        Some(504) => ErrorKind::TimedOut,
+        Some(_) => ErrorKind::Other,
+        None => ErrorKind::InvalidData,
+    };
+    Err(IoError::new(kind, format!("HTTP proxy answered {status_line}")))
+}
+
+/// A rule of the egress file, sending the destinations it matches through an upstream.
+///
+/// Destinations match when their host is in `domains` or `ips`, and their port in `ports`. Empty lists match everything.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EgressRule {
+    #[serde(default)]
+    domains: Vec<DomainPattern>,
+    #[serde(default)]
+    ips: Vec<IpRule>,
+    #[serde(default)]
+    ports: Vec<PortRule>,
+    upstream: String,
+}
+
+impl EgressRule {
+    fn matches(&self, destination: &Destination) -> bool {
+        let host_matches = match &destination.host {
+            _ if self.domains.is_empty() && self.ips.is_empty() => true,
+            Host::Ip(ip) => self.ips.iter().any(|rule| rule.contains(*ip)),
+            Host::Domain(domain, _) => {
+                let domain = normalize_domain(domain);
+                self.domains.iter().any(|pattern| pattern.matches(&domain))
+            }
+        };
+        host_matches && (self.ports.is_empty() || self.ports.iter().any(|rule| rule.matches(destination.port)))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct EgressFile {
+    /// The upstream of destinations matching no rule, `direct` if unset.
+    default: Option<String>,
+    /// Named proxies, such as `tor = "socks5://127.0.0.1:9050"`. `direct` needs no definition.
+    upstreams: HashMap<String, Proxy>,
+    /// Rules picking the upstream of destinations, the first matching one winning.
+    rules: Vec<EgressRule>,
+}
+
+#[derive(Debug)]
+pub enum EgressError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    UnknownUpstream(String),
+}
+
+impl fmt::Display for EgressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EgressError::Io(e) => write!(f, "Could not read egress file: {e}"),
+            EgressError::Parse(e) => write!(f, "Invalid egress file: {e}"),
+            EgressError::UnknownUpstream(name) => write!(f, "Invalid egress file: unknown upstream {name}"),
+        }
+    }
+}
+
+impl std::error::Error for EgressError {}
+
+/// Picks the proxy through which each destination is connected to, if any.
+#[derive(Debug, Default)]
+pub struct Egress {
+    rules: Vec<(EgressRule, Option<Proxy>)>,
+    default: Option<Proxy>,
+}
+
+impl Egress {
+    pub fn load(path: &Path) -> Result<Egress, EgressError> {
+        let content = std::fs::read_to_string(path).map_err(EgressError::Io)?;
+        Egress::from_file(toml::from_str(&content).map_err(EgressError::Parse)?)
+    }
+
+    /// Reads the egress rules from a table of the configuration file.
+    pub fn from_table(table: toml::Table) -> Result<Egress, EgressError> {
+        Egress::from_file(toml::Value::Table(table).try_into().map_err(EgressError::Parse)?)
+    }
+
+    fn from_file(file: EgressFile) -> Result<Egress, EgressError> {
+        let upstream = |name: &str| match (name, file.upstreams.get(name)) {
+            ("direct", _) => Ok(None),
+            (_, Some(proxy)) => Ok(Some(proxy.clone())),
+            (name, None) => Err(EgressError::UnknownUpstream(name.to_owned())),
+        };
+        let default = upstream(file.default.as_deref().unwrap_or("direct"))?;
+        let rules = file.rules.iter().map(|rule| upstream(&rule.upstream)).collect::<Result<Vec<_>, _>>()?;
+        Ok(Egress { rules: file.rules.into_iter().zip(rules).collect(), default })
+    }
+
+    /// Returns the proxy to connect to a destination through, or `None` to connect to it directly.
+    pub fn route(&self, destination: &Destination) -> Option<&Proxy> {
+        match self.rules.iter().find(|(rule, _)| rule.matches(destination)) {
+            Some((_, proxy)) => proxy.as_ref(),
+            None => self.default.as_ref(),
+        }
+    }
+}
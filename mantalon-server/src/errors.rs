@@ -1,35 +1,100 @@
-use std::str::FromStr;
-
-use actix_web::{http::StatusCode, ResponseError};
-use multiaddr::Multiaddr;
+use std::fmt;
+use crate::*;
 
+// Close codes sent instead of HTTP errors to clients that cannot read the status of a failed upgrade.
+// mantalon-client decodes them into `SendRequestError` variants, so both lists must be kept in sync.
+pub const CLOSE_INVALID_ADDRESS: u16 = 4400;
+pub const CLOSE_UNAUTHORIZED: u16 = 4401;
+pub const CLOSE_POLICY_DENIED: u16 = 4403;
+pub const CLOSE_DNS_FAILED: u16 = 4404;
+pub const CLOSE_IDLE_TIMEOUT: u16 = 4408;
+pub const CLOSE_TERMINATED: u16 = 4410;
+pub const CLOSE_RATE_LIMITED: u16 = 4429;
+pub const CLOSE_CONNECT_FAILED: u16 = 4500;
+pub const CLOSE_CONNECT_REFUSED: u16 = 4502;
+pub const CLOSE_UNAVAILABLE: u16 = 4503;
+pub const CLOSE_CONNECT_TIMEOUT: u16 = 4504;
+pub const CLOSE_QUOTA_EXCEEDED: u16 = 4509;
 
+/// The reasons a relay could not be opened.
 #[derive(Debug)]
 pub enum MantalonError {
-    InvalidAddr { addr: String, error: <Multiaddr as FromStr>::Err },
+    InvalidAddr(multiaddr::Error),
     MissingProtocol,
-    UnsupportedProtocol { protocol: String },
-    ConnectionError { error: std::io::Error },
+    UnsupportedProtocol(String),
+    Unauthorized(TicketError),
+    ShuttingDown,
+    RateLimited(LimitError),
+    QuotaExceeded(QuotaExceeded),
+    PolicyDenied(PolicyViolation),
+    Banned(String),
+    DnsFailed(String),
+    ConnectionError { addrs: Vec<SocketAddr>, error: Option<std::io::Error> },
+    ProxyError { destination: Multiaddr, proxy: String, error: std::io::Error },
 }
 
-impl std::fmt::Display for MantalonError {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl fmt::Display for MantalonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            MantalonError::InvalidAddr { addr, error } => write!(f, "Invalid address {addr}: {error}"),
-            MantalonError::MissingProtocol => write!(f, "Missing protocol"),
-            MantalonError::UnsupportedProtocol { protocol } => write!(f, "Unsupported protocol {protocol}"),
-            MantalonError::ConnectionError { error } => write!(f, "Connection error: {error}"),
+            MantalonError::InvalidAddr(e) => write!(f, "Invalid address: {e}"),
+            MantalonError::MissingProtocol => write!(f, "Incomplete address. Try something like /ip4/127.0.0.1/tcp/8080"),
+            MantalonError::UnsupportedProtocol(protocol) => write!(f, "Unsupported protocol: {protocol}"),
+            MantalonError::Unauthorized(e) => write!(f, "Unauthorized: {e}"),
+            MantalonError::ShuttingDown => write!(f, "Service unavailable: the server is shutting down"),
+            MantalonError::RateLimited(e) => write!(f, "Too many requests: {e}"),
+            MantalonError::QuotaExceeded(e) => write!(f, "Too many requests: {e}"),
+            MantalonError::PolicyDenied(violation) => write!(f, "Destination refused by policy: {violation}"),
+            MantalonError::Banned(reason) => write!(f, "Banned: {reason}"),
+            MantalonError::DnsFailed(domain) => write!(f, "Could not resolve {domain}"),
+            MantalonError::ConnectionError { addrs, error: Some(e) } => write!(f, "Could not connect to any address: {addrs:?}: {e}"),
+            MantalonError::ConnectionError { addrs, error: None } => write!(f, "Could not connect to any address: {addrs:?}"),
+            MantalonError::ProxyError { destination, proxy, error } => write!(f, "Could not connect to {destination} through {proxy}: {error}"),
         }
     }
 }
 
-impl ResponseError for MantalonError {
-    fn status_code(&self) -> StatusCode {
+impl std::error::Error for MantalonError {}
+
+impl MantalonError {
+    pub fn status_code(&self) -> StatusCode {
         match self {
-            MantalonError::InvalidAddr { .. } => StatusCode::BAD_REQUEST,
+            MantalonError::InvalidAddr(_) => StatusCode::BAD_REQUEST,
             MantalonError::MissingProtocol => StatusCode::BAD_REQUEST,
-            MantalonError::UnsupportedProtocol { .. } => StatusCode::INTERNAL_SERVER_ERROR,
-            MantalonError::ConnectionError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            MantalonError::UnsupportedProtocol(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            MantalonError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            MantalonError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+            MantalonError::RateLimited(LimitError::TooManyRelays) => StatusCode::SERVICE_UNAVAILABLE,
+            MantalonError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            MantalonError::QuotaExceeded(_) => StatusCode::TOO_MANY_REQUESTS,
+            MantalonError::PolicyDenied(_) | MantalonError::Banned(_) => StatusCode::FORBIDDEN,
+            MantalonError::DnsFailed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            MantalonError::ConnectionError { .. } | MantalonError::ProxyError { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn close_code(&self) -> u16 {
+        match self {
+            MantalonError::InvalidAddr(_) | MantalonError::MissingProtocol | MantalonError::UnsupportedProtocol(_) => CLOSE_INVALID_ADDRESS,
+            MantalonError::Unauthorized(_) => CLOSE_UNAUTHORIZED,
+            MantalonError::ShuttingDown | MantalonError::RateLimited(LimitError::TooManyRelays) => CLOSE_UNAVAILABLE,
+            MantalonError::RateLimited(_) => CLOSE_RATE_LIMITED,
+            MantalonError::QuotaExceeded(_) => CLOSE_QUOTA_EXCEEDED,
+            MantalonError::PolicyDenied(_) | MantalonError::Banned(_) => CLOSE_POLICY_DENIED,
+            MantalonError::DnsFailed(_) => CLOSE_DNS_FAILED,
+            MantalonError::ConnectionError { error: Some(e), .. } | MantalonError::ProxyError { error: e, .. } => match e.kind() {
+                std::io::ErrorKind::ConnectionRefused => CLOSE_CONNECT_REFUSED,
+                std::io::ErrorKind::TimedOut => CLOSE_CONNECT_TIMEOUT,
+                _ => CLOSE_CONNECT_FAILED,
+            },
+            MantalonError::ConnectionError { error: None, .. } => CLOSE_CONNECT_FAILED,
+        }
+    }
+
+    /// How long the client should wait before retrying, for errors that are expected to go away.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            MantalonError::RateLimited(e) => Some(e.retry_after()),
+            _ => None,
         }
     }
 }
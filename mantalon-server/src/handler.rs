@@ -1,6 +1,42 @@
 use crate::*;
 
-pub async fn http_handler(req: Request<Incoming>, dns_cache: DnsCache, _args: &'static Args) -> Result<Response<EitherBody<FullBody, hyper_staticfile::Body>>, BoxedError> {
+/// Returns the address of the client, which is taken from the `X-Forwarded-For` header set by the reverse proxy for connections on unix sockets.
+fn client_ip<B>(req: &Request<B>, peer_ip: Option<IpAddr>) -> Option<IpAddr> {
+    if peer_ip.is_some() {
+        return peer_ip;
+    }
+    let forwarded_for = req.headers().get("x-forwarded-for")?.to_str().ok()?;
+    forwarded_for.rsplit(',').next()?.trim().parse().ok()
+}
+
+/// Whether the client asked for failures to be reported in a close frame, as browsers cannot read the status of a failed upgrade.
+fn wants_close_reports<B>(req: &Request<B>) -> bool {
+    req.uri().query().is_some_and(|query| query.split('&').any(|pair| pair == "report=close"))
+}
+
+pub async fn http_handler(req: Request<Incoming>, peer_ip: Option<IpAddr>, state: &'static ServerState) -> Result<Response<EitherBody<FullBody, hyper_staticfile::Body>>, BoxedError> {
+    // Requests are served with the configuration current when they arrive
+    let settings = state.settings();
+
+    // Expose metrics next to the relays when enabled
+    if settings.args.metrics && req.uri().path() == "/metrics" {
+        let mut response = Response::new(FullBody::from(state.metrics.render()));
+        response.headers_mut().insert("content-type", HeaderValue::from_static("text/plain; version=0.0.4"));
+        return Ok(response.map(EitherBody::Left));
+    }
+
+    let is_connect = req.uri().path().starts_with("/mantalon-connect");
+    if let (false, Some(static_files)) = (is_connect, &settings.static_files) {
+        return Ok(static_files.serve(&req).await?.map(EitherBody::Right));
+    }
+    let response = connect_handler(req, peer_ip, state, settings).await?;
+    if is_connect && response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        state.metrics.upgrade_rejected(response.status());
+    }
+    Ok(response)
+}
+
+async fn connect_handler(req: Request<Incoming>, peer_ip: Option<IpAddr>, state: &'static ServerState, settings: Arc<Settings>) -> Result<Response<EitherBody<FullBody, hyper_staticfile::Body>>, BoxedError> {
     // Check path
     let path = req.uri().path();
     if !path.starts_with("/mantalon-connect/") && path != "/mantalon-connect" {
@@ -25,80 +61,34 @@ pub async fn http_handler(req: Request<Incoming>, dns_cache: DnsCache, _args: &'
         return Ok(response.map(EitherBody::Left));
     }
 
-    // Extract the address from the path
-    let addr = &path[17..];
-    let addr: Multiaddr = match addr.parse() {
-        Ok(addr) => addr,
-        Err(e) => {
-            debug!("Invalid address: {e}");
-            let mut response = Response::new(FullBody::from(format!("Invalid address: {e}")));
-            *response.status_mut() = StatusCode::BAD_REQUEST;
-            return Ok(response.map(EitherBody::Left));
-        }
-    };
+    // Only let the allowed origins use the relay from browsers
+    if let Err(origin) = check_origin(&req, &settings.args.allowed_origins) {
+        info!("Refused upgrade from origin {origin}");
+        let mut response = Response::new(FullBody::from(format!("Origin {origin} is not allowed")));
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        return Ok(response.map(EitherBody::Left));
+    }
 
-    // Extract the IP address from the multiaddr
-    let mut protocols = addr.iter();
-    let ips = match protocols.next() {
-        Some(Protocol::Ip4(ip)) => vec![IpAddr::V4(ip)],
-        Some(Protocol::Ip6(ip)) => vec![IpAddr::V6(ip)],
-        Some(Protocol::Dns(domain) | Protocol::Dnsaddr(domain)) => resolve(dns_cache, &domain, SocketAddr::new(IpAddr::V4(Ipv4Addr::from([8,8,8,8])), 53)).await,
-        Some(p) => {
-            debug!("Unsupported protocol: {p}");
-            let mut response = Response::new(FullBody::from(format!("Unsupported protocol: {p}")));
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return Ok(response.map(EitherBody::Left));
-        }
-        None => {
-            debug!("Incomplete address");
-            let mut response = Response::new(FullBody::from("Incomplete address. Try something like /ip4/127.0.0.1/tcp/8080"));
-            *response.status_mut() = StatusCode::BAD_REQUEST;
-            return Ok(response.map(EitherBody::Left));
-        }
+    // Check the destination and connect to it, unless the websocket is to the bare endpoint and carries multiplexed streams
+    let multiplexed = req.uri().path().trim_end_matches('/') == "/mantalon-connect";
+    let relay = match (multiplexed, state.shutdown.is_stopping()) {
+        (true, true) => Err(MantalonError::ShuttingDown),
+        (true, false) => Ok(None),
+        (false, _) => prepare_relay(&req, peer_ip, state, &settings).await.map(Some),
     };
-
-    // Build the underlying transport
-    let (transport_reader, transport_write): (Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWrite + Send + Unpin>) = match protocols.next() {
-        Some(Protocol::Tcp(port)) => {
-            'try_ip: {
-                for ip in &ips {
-                    let addr = SocketAddr::new(*ip, port);
-                    let stream = match TcpStream::connect(addr).await {
-                        Ok(stream) => stream,
-                        Err(e) => {
-                            error!("Could not connect to address: {e}");
-                            continue;
-                        },
-                    };
-                    let (transport_reader, transport_write) = stream.into_split();
-                    break 'try_ip (Box::new(transport_reader), Box::new(transport_write))
-                }
-                let mut response = Response::new(FullBody::from(format!("Could not connect to any ip: {ips:?}")));
-                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                return Ok(response.map(EitherBody::Left));
+    let close_on_error = wants_close_reports(&req);
+    if let Err(e) = &relay {
+        debug!("Could not open relay: {e}");
+        if !close_on_error {
+            let mut response = Response::new(FullBody::from(e.to_string()));
+            *response.status_mut() = e.status_code();
+            if let Some(retry_after) = e.retry_after() {
+                let retry_after = retry_after.as_secs_f64().ceil() as u64;
+                response.headers_mut().insert("retry-after", HeaderValue::from(retry_after.max(1)));
             }
-        }
-        Some(p) => {
-            debug!("Unsupported protocol: {p}");
-            let mut response = Response::new(FullBody::from(format!("Unsupported protocol: {p}")));
-            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
             return Ok(response.map(EitherBody::Left));
         }
-        None => {
-            debug!("Incomplete address");
-            let mut response = Response::new(FullBody::from("Incomplete address. Try something like /ip4/127.0.0.1/tcp/8080"));
-            *response.status_mut() = StatusCode::BAD_REQUEST;
-            return Ok(response.map(EitherBody::Left));
-        }
-    };
-    debug!("Transport established to {addr}");
-
-    // Ensure there are no more protocols
-    if let Some(p) = protocols.next() {
-        debug!("Unsupported protocol: {p}");
-        let mut response = Response::new(FullBody::from(format!("Unsupported protocol: {p}")));
-        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-        return Ok(response.map(EitherBody::Left));
+        state.metrics.upgrade_rejected(e.status_code());
     }
 
     // Create handshake server
@@ -112,7 +102,7 @@ pub async fn http_handler(req: Request<Incoming>, dns_cache: DnsCache, _args: &'
     // }
 
     // Attempt the upgrade.
-    let response = match server.receive_request(&req) {
+    let mut response = match server.receive_request(&req) {
         Ok(response) => response,
         Err(e) => {
             error!("Could not upgrade connection: {e}");
@@ -122,41 +112,286 @@ pub async fn http_handler(req: Request<Incoming>, dns_cache: DnsCache, _args: &'
         }
     };
 
+    // Echo the protocol the ticket was found in, as browsers fail the connection otherwise
+    let ticket_protocol = settings.ticket_key.as_ref().and_then(|_| extract_ticket(&req)).and_then(|(_, protocol)| protocol);
+    if let Some(protocol) = ticket_protocol.and_then(|p| HeaderValue::from_str(&p).ok()) {
+        response.headers_mut().insert("sec-websocket-protocol", protocol);
+    }
+
+    // Report the failure in the close frame of the accepted websocket
+    let client_ip = client_ip(&req, peer_ip);
+    let PreparedRelay { addr, remote_addr, transport, permit, account } = match relay {
+        Ok(Some(relay)) => relay,
+        Ok(None) => {
+            let session = MuxSession::new(client_ip, ticket(&req, &settings), state);
+            state.shutdown.spawn_relay(session.run(server, req));
+            return Ok(response.map(|()| FullBody::default()).map(EitherBody::Left));
+        }
+        Err(e) => {
+            let (handshake_timeout, keepalive) = (settings.args.handshake_timeout, settings.args.keepalive());
+            tokio::spawn(async move {
+                match tokio::time::timeout(Duration::from_secs(handshake_timeout), handshake(server, req, None, keepalive)).await {
+                    Ok(Ok((sender, receiver, closer))) => {
+                        drop((sender, receiver));
+                        closer.close(e.close_code(), &e.to_string()).await;
+                    }
+                    Ok(Err(e)) => error!("Could not complete handshake: {e}"),
+                    Err(_) => {
+                        debug!("Websocket handshake timed out");
+                        state.metrics.timed_out("handshake");
+                    }
+                }
+            });
+            return Ok(response.map(|()| FullBody::default()).map(EitherBody::Left));
+        }
+    };
+
     // Return the response we're given back and spawn a task to handle the long-running WebSocket server
-    tokio::spawn(async move {
-        let (sender, receiver) = match handshake(server, req).await {
-            Ok((sender, receiver)) => (sender, receiver),
-            Err(e) => {
+    state.shutdown.spawn_relay(async move {
+        let args = &settings.args;
+        let _permit = permit;
+        let meter = Arc::new(state.bandwidth.meter(account.as_ref(), &state.metrics));
+        let (max_message_size, idle_timeout) = match transport {
+            Transport::Stream(..) => (Some(args.max_message_size as usize), args.idle_timeout),
+            Transport::Datagram(_) => (Some(args.udp_max_datagram_size), args.udp_idle_timeout),
+        };
+        let (sender, receiver, closer) = match tokio::time::timeout(Duration::from_secs(args.handshake_timeout), handshake(server, req, max_message_size, args.keepalive())).await {
+            Ok(Ok(handshake)) => handshake,
+            Ok(Err(e)) => {
                 error!("Could not complete handshake: {e}");
                 return;
             }
+            Err(_) => {
+                debug!("Websocket handshake timed out");
+                state.metrics.timed_out("handshake");
+                return;
+            }
+        };
+
+        let registered = state.relays.register(&addr, remote_addr, client_ip, false, &meter);
+        debug!("Relay {} now operational", registered.id());
+        let heartbeat = receiver.heartbeat();
+        let _active = state.metrics.relay_started();
+        let relay = async {
+            match transport {
+                Transport::Stream(transport_reader, transport_write) => {
+                    relay_stream(receiver, sender, &closer, transport_reader, transport_write, args.relay_buffer_size as usize, Arc::clone(&meter)).await
+                }
+                Transport::Datagram(socket) => {
+                    let socket = Arc::new(socket);
+                    let fut1 = relay_websocket_to_udp(receiver, Arc::clone(&socket), Arc::clone(&meter));
+                    let fut2 = relay_udp_to_websocket(socket, sender, args.udp_max_datagram_size, Arc::clone(&meter));
+                    tokio::select! {
+                        r = fut1 => { debug!("Websocket to UDP task finished"); r },
+                        r = fut2 => { debug!("UDP to websocket task finished"); r },
+                    }
+                }
+            }
         };
-        let fut1 = relay_websocket_to_transport(receiver, transport_write);
-        let fut2 = relay_transport_to_websocket(transport_reader, sender);
-        
-        debug!("Relay now operational");
-        tokio::select! {
-            _ = fut1 => debug!("Websocket to transport task finished"),
-            _ = fut2 => debug!("Transport to websocket task finished"),
+
+        // The relay futures own the sender and receiver, so they are dropped by the time the closer is used
+        let close = tokio::select! {
+            result = relay => result.err().map(|e| {
+                info!("Closing relay to {addr}: {e}");
+                (CLOSE_QUOTA_EXCEEDED, "Daily quota exceeded")
+            }),
+            () = meter.idle(Duration::from_secs(idle_timeout)) => {
+                debug!("Closing relay to {addr} after {idle_timeout}s without traffic");
+                state.metrics.timed_out("idle");
+                Some((CLOSE_IDLE_TIMEOUT, "Idle timeout"))
+            }
+            // Writing a close frame to a vanished client could block until the kernel gives up, so the connection is just dropped
+            () = heartbeat.dead() => {
+                debug!("Dropping relay to {addr} as the client stopped answering pings");
+                state.metrics.timed_out("pong");
+                None
+            }
+            () = registered.killed() => {
+                info!("Terminating relay {} to {addr} at the request of an administrator", registered.id());
+                Some((CLOSE_TERMINATED, "Terminated by an administrator"))
+            }
+            () = state.shutdown.going_away() => Some((CLOSE_GOING_AWAY, "Server shutting down")),
+        };
+        if let Some((code, reason)) = close {
+            closer.close(code, reason).await;
         }
     });
     Ok(response.map(|()| FullBody::default()).map(EitherBody::Left))
 }
 
-pub type WsSender = Sender<BufReader<BufWriter<Compat<TokioIo<Upgraded>>>>>;
-pub type WsReceiver = Receiver<BufReader<BufWriter<Compat<TokioIo<Upgraded>>>>>;
-
-pub async fn handshake(server: Server, req: Request<Incoming>) -> Result<(WsSender, WsReceiver), BoxedError> {
-    // The negotiation to upgrade to a WebSocket connection has been successful so far. Next, we get back the underlying
-    // stream using `hyper::upgrade::on`, and hand this to a Soketto server to use to handle the WebSocket communication
-    // on this socket.
-    //
-    // Note: awaiting this won't succeed until the handshake response has been returned to the client, so this must be
-    // spawned on a separate task so as not to block that response being handed back.
-    let stream = hyper::upgrade::on(req).await?;
-    let io = TokioIo::new(stream);
-    let stream = BufReader::new(BufWriter::new(io.compat()));
-
-    // Get back a reader and writer that we can use to send and receive websocket messages.
-    Ok(server.into_builder(stream).finish())
+/// A relay whose destination has been checked and connected to, waiting for the websocket upgrade.
+pub struct PreparedRelay {
+    pub addr: Multiaddr,
+    /// The address connected to, which is the proxy's for destinations reached through egress proxies.
+    pub remote_addr: Option<SocketAddr>,
+    pub transport: Transport,
+    pub permit: RelayPermit,
+    pub account: Option<LimitKey>,
+}
+
+/// The ticket the client presented, if the server requires them.
+fn ticket<B>(req: &Request<B>, settings: &Settings) -> Option<String> {
+    settings.ticket_key.as_ref().and_then(|_| extract_ticket(req)).map(|(ticket, _)| ticket)
+}
+
+async fn prepare_relay(req: &Request<Incoming>, peer_ip: Option<IpAddr>, state: &'static ServerState, settings: &Settings) -> Result<PreparedRelay, MantalonError> {
+    // Extract the address from the path
+    let addr: Multiaddr = req.uri().path()[17..].parse().map_err(MantalonError::InvalidAddr)?;
+    open_relay(addr, client_ip(req, peer_ip), ticket(req, settings), state, settings).await
+}
+
+/// Checks that a client can connect to a destination, and connects to it.
+pub async fn open_relay(addr: Multiaddr, client_ip: Option<IpAddr>, ticket: Option<String>, state: &'static ServerState, settings: &Settings) -> Result<PreparedRelay, MantalonError> {
+    let args = &settings.args;
+
+    // Refuse new relays while shutting down
+    if state.shutdown.is_stopping() {
+        return Err(MantalonError::ShuttingDown);
+    }
+
+    // Refuse banned clients before doing anything on their behalf
+    if let Err(e) = state.bans.check_client(client_ip) {
+        info!("Refused relay to {addr}: {e}");
+        return Err(e);
+    }
+
+    // Check the ticket before doing anything on behalf of the client
+    let mut limit_keys: Vec<LimitKey> = client_ip.map(LimitKey::ip).into_iter().collect();
+    if let Some(ticket_key) = &settings.ticket_key {
+        let result = match ticket {
+            Some(ticket) => {
+                let result = ticket_key.verify(&ticket, &addr.to_string(), client_ip);
+                limit_keys.push(LimitKey::Ticket(ticket));
+                result
+            }
+            None => Err(TicketError::Missing),
+        };
+        result.map_err(MantalonError::Unauthorized)?;
+    }
+
+    // Traffic is accounted to the ticket if there is one, or to the IP address otherwise
+    let account = limit_keys.last().cloned();
+    if let Err(e) = state.bandwidth.check_quota(account.as_ref()) {
+        info!("Refused relay to {addr}: {e}");
+        return Err(MantalonError::QuotaExceeded(e));
+    }
+
+    // Count the relay against the limits of the client
+    let permit = match state.limiter.acquire(limit_keys) {
+        Ok(permit) => permit,
+        Err(e) => {
+            info!("Refused relay to {addr}: {e}");
+            return Err(MantalonError::RateLimited(e));
+        }
+    };
+
+    // Extract the destinations from the multiaddr, which are listed in DNS for `/dnsaddr`
+    let destinations = match addr.iter().next() {
+        Some(Protocol::Dnsaddr(domain)) => {
+            let suffix = addr.iter().skip(1).collect::<Multiaddr>();
+            let destinations = resolve_dnsaddr(&domain, &suffix, &state.dns_cache).await;
+            if destinations.is_empty() {
+                state.metrics.connect_failed("resolve");
+                return Err(MantalonError::DnsFailed(format!("/dnsaddr/{domain}")));
+            }
+            destinations
+        }
+        _ => vec![Destination::parse(&addr)?],
+    };
+
+    // Check the destinations, all of which must use the transport of the first one.
+    // Those reached directly are resolved, while egress proxies resolve the others themselves.
+    let udp = destinations[0].udp;
+    let mut addrs = Vec::new();
+    let mut proxied = Vec::new();
+    let mut first_error = None;
+    for destination in destinations.iter().filter(|destination| destination.udp == udp) {
+        let result = match settings.egress.route(destination) {
+            None => destination.resolve(&settings.policy, state).await
+                .and_then(|mut resolved| state.bans.check_destination(destination, &mut resolved).map(|()| addrs.extend(resolved))),
+            Some(proxy) if udp => Err(MantalonError::UnsupportedProtocol(format!("{destination} would go through {proxy}, which only carries TCP"))),
+            Some(proxy) => destination.check_unresolved(&settings.policy)
+                .and_then(|()| state.bans.check_destination(destination, &mut Vec::new()))
+                .map(|()| proxied.push((destination, proxy))),
+        };
+        if let Err(e) = result {
+            first_error.get_or_insert(e);
+        }
+    }
+    if let (true, true, Some(e)) = (addrs.is_empty(), proxied.is_empty(), first_error) {
+        state.metrics.connect_failed(match e {
+            MantalonError::PolicyDenied(_) | MantalonError::Banned(_) => "policy",
+            _ => "resolve",
+        });
+        return Err(e);
+    }
+
+    // Build the underlying transport
+    let transport = match udp {
+        // Direct addresses are raced first, then proxies are tried in turn
+        false => 'tcp: {
+            let mut last_error = None;
+            if !addrs.is_empty() {
+                let attempt_delay = Duration::from_millis(args.connect_attempt_delay);
+                let attempt_timeout = Duration::from_secs(args.connect_attempt_timeout);
+                let timeout = Duration::from_secs(args.connect_timeout);
+                match happy_eyeballs_connect(&addrs, attempt_delay, attempt_timeout, timeout).await {
+                    Ok(stream) => break 'tcp Ok(stream),
+                    Err(e) => last_error = Some(MantalonError::ConnectionError { addrs: addrs.clone(), error: Some(e) }),
+                }
+            }
+            for (destination, proxy) in proxied {
+                let host = match &destination.host {
+                    Host::Ip(ip) => ip.to_string(),
+                    Host::Domain(domain, _) => domain.clone(),
+                };
+                let result = match tokio::time::timeout(Duration::from_secs(args.connect_timeout), proxy.connect(&host, destination.port)).await {
+                    Ok(result) => result,
+                    Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, format!("connecting timed out after {}s", args.connect_timeout))),
+                };
+                match result {
+                    Ok(stream) => {
+                        debug!("Connected to {destination} through {proxy}");
+                        break 'tcp Ok(stream);
+                    }
+                    Err(error) => last_error = Some(MantalonError::ProxyError { destination: destination.addr.clone(), proxy: proxy.to_string(), error }),
+                }
+            }
+            Err(last_error.unwrap_or(MantalonError::ConnectionError { addrs, error: None }))
+        }
+        .map(|stream| {
+            let keepalive = (args.tcp_keepalive != 0).then(|| Duration::from_secs(args.tcp_keepalive));
+            if let Err(e) = set_tcp_options(&stream, keepalive) {
+                debug!("Could not set TCP options: {e}");
+            }
+            let remote_addr = stream.peer_addr().ok();
+            let (transport_reader, transport_write) = stream.into_split();
+            (Transport::Stream(Box::new(transport_reader), Box::new(transport_write)), remote_addr)
+        }),
+        // UDP sockets connect instantly, so there is nothing to race
+        true => 'udp: {
+            let mut last_error = None;
+            for addr in &addrs {
+                match connect_udp(*addr).await {
+                    Ok(socket) => break 'udp Ok((Transport::Datagram(socket), Some(*addr))),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            Err(MantalonError::ConnectionError { addrs, error: last_error })
+        }
+    };
+    match transport {
+        Ok((transport, remote_addr)) => {
+            debug!("Transport established to {addr}");
+            Ok(PreparedRelay { addr, remote_addr, transport, permit, account })
+        }
+        Err(e) => {
+            error!("Could not connect to {addr}: {e}");
+            state.metrics.connect_failed(match &e {
+                MantalonError::ConnectionError { error: Some(e), .. } | MantalonError::ProxyError { error: e, .. } => connect_failure_reason(e),
+                _ => "other",
+            });
+            Err(e)
+        }
+    }
 }
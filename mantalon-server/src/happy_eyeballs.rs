@@ -0,0 +1,163 @@
+use std::io::{Error as IoError, ErrorKind};
+use futures::{stream::FuturesUnordered, StreamExt};
+use crate::*;
+
+/// Orders addresses so that families alternate, starting with the family of the first address (RFC 8305 section 4).
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.iter().partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    preferred.reverse();
+    other.reverse();
+
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    loop {
+        match (preferred.pop(), other.pop()) {
+            (None, None) => break,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+    interleaved
+}
+
+/// Connects to the first of `addrs` that accepts a TCP connection, following Happy Eyeballs v2 (RFC 8305).
+///
+/// Attempts are started `attempt_delay` apart, or as soon as the previous one fails, and run concurrently.
+/// Each of them is abandoned after `attempt_timeout`, and the whole process after `timeout`.
+pub async fn happy_eyeballs_connect(addrs: &[SocketAddr], attempt_delay: Duration, attempt_timeout: Duration, timeout: Duration) -> Result<TcpStream, IoError> {
+    let race = async {
+        let mut pending = interleave_families(addrs).into_iter().peekable();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = None;
+        loop {
+            match pending.next() {
+                Some(addr) => {
+                    attempts.push(async move {
+                        let result = match tokio::time::timeout(attempt_timeout, TcpStream::connect(addr)).await {
+                            Ok(result) => result,
+                            Err(_) => Err(IoError::new(ErrorKind::TimedOut, format!("connecting to {addr} timed out"))),
+                        };
+                        (addr, result)
+                    });
+                }
+                None if attempts.is_empty() => break,
+                None => (),
+            }
+
+            tokio::select! {
+                Some((addr, result)) = attempts.next() => match result {
+                    Ok(stream) => {
+                        debug!("Connected to {addr}");
+                        return Ok(stream);
+                    }
+                    Err(e) => {
+                        debug!("Could not connect to {addr}: {e}");
+                        last_error = Some(e);
+                    }
+                },
+                () = tokio::time::sleep(attempt_delay), if pending.peek().is_some() => (),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| IoError::new(ErrorKind::InvalidInput, "no address to connect to")))
+    };
+
+    match tokio::time::timeout(timeout, race).await {
+        Ok(result) => result,
+        Err(_) => Err(IoError::new(ErrorKind::TimedOut, format!("connecting timed out after {timeout:?}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use socket2::{Domain, Socket, Type};
+    use super::*;
+
+    fn addrs(addrs: &[&str]) -> Vec<SocketAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    /// Returns the address of a port that refuses connections.
+    async fn refusing() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    /// Returns a listener whose backlog is full, so that connecting to it hangs, along with what keeps it full.
+    async fn blackhole() -> (Socket, SocketAddr, Vec<TcpStream>) {
+        let socket = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        socket.bind(&"127.0.0.1:0".parse::<SocketAddr>().unwrap().into()).unwrap();
+        socket.listen(0).unwrap();
+        let addr = socket.local_addr().unwrap().as_socket().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(stream) = tokio::time::timeout(Duration::from_millis(200), TcpStream::connect(addr)).await {
+            queued.push(stream.unwrap());
+        }
+        (socket, addr, queued)
+    }
+
+    #[test]
+    fn families_are_interleaved() {
+        let v6 = addrs(&["[2001:db8::1]:443", "[2001:db8::2]:443", "[2001:db8::3]:443"]);
+        assert_eq!(interleave_families(&v6), v6);
+
+        let v4 = addrs(&["192.0.2.1:443", "192.0.2.2:443"]);
+        assert_eq!(interleave_families(&v4), v4);
+
+        let mixed = addrs(&["[2001:db8::1]:443", "[2001:db8::2]:443", "192.0.2.1:443", "[2001:db8::3]:443", "192.0.2.2:443"]);
+        let expected = addrs(&["[2001:db8::1]:443", "192.0.2.1:443", "[2001:db8::2]:443", "192.0.2.2:443", "[2001:db8::3]:443"]);
+        assert_eq!(interleave_families(&mixed), expected);
+
+        let mixed = addrs(&["192.0.2.1:443", "[2001:db8::1]:443", "192.0.2.2:443", "192.0.2.3:443"]);
+        let expected = addrs(&["192.0.2.1:443", "[2001:db8::1]:443", "192.0.2.2:443", "192.0.2.3:443"]);
+        assert_eq!(interleave_families(&mixed), expected);
+
+        assert!(interleave_families(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_attempts_start_the_next_at_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let start = Instant::now();
+        let stream = happy_eyeballs_connect(&[refusing().await, target], Duration::from_secs(10), Duration::from_secs(10), Duration::from_secs(20)).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), target);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn attempts_time_out_on_their_own() {
+        let (_socket, hanging, _queued) = blackhole().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+
+        // The hanging attempt is abandoned after its own timeout, which starts the next one
+        let start = Instant::now();
+        let stream = happy_eyeballs_connect(&[hanging, target], Duration::from_secs(10), Duration::from_millis(200), Duration::from_secs(20)).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), target);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let e = happy_eyeballs_connect(&[hanging], Duration::from_secs(10), Duration::from_millis(200), Duration::from_secs(20)).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(e.to_string().contains(&hanging.to_string()), "{e}");
+    }
+
+    #[tokio::test]
+    async fn connecting_times_out_as_a_whole() {
+        let (_socket, hanging, _queued) = blackhole().await;
+        let start = Instant::now();
+        let e = happy_eyeballs_connect(&[hanging], Duration::from_millis(50), Duration::from_secs(10), Duration::from_millis(300)).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(e.to_string().contains("after"), "{e}");
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn refused_connections_are_reported() {
+        let e = happy_eyeballs_connect(&[refusing().await], Duration::from_secs(10), Duration::from_secs(10), Duration::from_secs(20)).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionRefused);
+        let e = happy_eyeballs_connect(&[], Duration::from_secs(10), Duration::from_secs(10), Duration::from_secs(20)).await.unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{collections::HashMap, fmt, hash::Hash, sync::Mutex};
+use crate::*;
+
+/// Identifies a client for the purpose of rate limiting.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LimitKey {
+    /// IPv6 clients are identified by their /64 prefix, as they usually control all of it.
+    Ip(IpAddr),
+    Ticket(String),
+}
+
+impl LimitKey {
+    pub fn ip(ip: IpAddr) -> LimitKey {
+        match ip.to_canonical() {
+            IpAddr::V6(ip) => LimitKey::Ip(IpAddr::V6((u128::from(ip) & !(u64::MAX as u128)).into())),
+            ip => LimitKey::Ip(ip),
+        }
+    }
+}
+
+impl fmt::Display for LimitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitKey::Ip(IpAddr::V6(ip)) => write!(f, "{ip}/64"),
+            LimitKey::Ip(ip) => write!(f, "{ip}"),
+            LimitKey::Ticket(ticket) => write!(f, "ticket {}", ticket.get(..16).unwrap_or(ticket)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LimitError {
+    TooManyRelays,
+    TooManyClientRelays(LimitKey),
+    RateLimited(LimitKey, Duration),
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitError::TooManyRelays => write!(f, "the server has too many active relays"),
+            LimitError::TooManyClientRelays(key) => write!(f, "{key} has too many active relays"),
+            LimitError::RateLimited(key, retry_after) => write!(f, "{key} opens relays too fast, retry in {retry_after:?}"),
+        }
+    }
+}
+
+impl LimitError {
+    /// How long the client should wait before retrying.
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            LimitError::RateLimited(_, retry_after) => *retry_after,
+            _ => Duration::from_secs(1),
+        }
+    }
+}
+
+struct ClientState {
+    tokens: f64,
+    last_refill: Instant,
+    active: usize,
+}
+
+/// The limits of a [`RelayLimiter`], which can change while it is running.
+#[derive(Clone, Copy)]
+struct RelayLimits {
+    /// New relays allowed per second and per client.
+    rate: f64,
+    /// New relays a client can open at once before being limited by `rate`.
+    burst: f64,
+    max_per_client: usize,
+    max_total: usize,
+}
+
+/// Limits the rate of new relays and the number of concurrent relays, per client and globally.
+pub struct RelayLimiter {
+    limits: Mutex<RelayLimits>,
+    clients: Mutex<HashMap<LimitKey, ClientState>>,
+    total: Mutex<usize>,
+}
+
+/// Counts a relay as active until dropped.
+pub struct RelayPermit {
+    limiter: &'static RelayLimiter,
+    keys: Vec<LimitKey>,
+}
+
+impl RelayLimiter {
+    pub fn new(rate: f64, burst: f64, max_per_client: usize, max_total: usize) -> RelayLimiter {
+        RelayLimiter {
+            limits: Mutex::new(RelayLimits { rate, burst: burst.max(1.0), max_per_client, max_total }),
+            clients: Mutex::new(HashMap::new()),
+            total: Mutex::new(0),
+        }
+    }
+
+    /// Changes the limits, which apply to the relays opened from now on.
+    pub fn reconfigure(&self, rate: f64, burst: f64, max_per_client: usize, max_total: usize) {
+        *self.limits.lock().unwrap() = RelayLimits { rate, burst: burst.max(1.0), max_per_client, max_total };
+    }
+
+    /// Checks all limits of all `keys`, and if none is exceeded, counts a new relay for them.
+    pub fn acquire(&'static self, keys: Vec<LimitKey>) -> Result<RelayPermit, LimitError> {
+        let RelayLimits { rate, burst, max_per_client, max_total } = *self.limits.lock().unwrap();
+        let mut total = self.total.lock().unwrap();
+        if *total >= max_total {
+            return Err(LimitError::TooManyRelays);
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        let now = Instant::now();
+        for key in &keys {
+            let client = clients.entry(key.clone()).or_insert_with(|| ClientState { tokens: burst, last_refill: now, active: 0 });
+            client.tokens = (client.tokens + now.duration_since(client.last_refill).as_secs_f64() * rate).min(burst);
+            client.last_refill = now;
+            if client.active >= max_per_client {
+                return Err(LimitError::TooManyClientRelays(key.clone()));
+            }
+            if client.tokens < 1.0 {
+                let retry_after = match rate > 0.0 {
+                    true => Duration::from_secs_f64((1.0 - client.tokens) / rate),
+                    false => Duration::from_secs(3600),
+                };
+                return Err(LimitError::RateLimited(key.clone(), retry_after));
+            }
+        }
+
+        for key in &keys {
+            if let Some(client) = clients.get_mut(key) {
+                client.tokens -= 1.0;
+                client.active += 1;
+            }
+        }
+        *total += 1;
+
+        Ok(RelayPermit { limiter: self, keys })
+    }
+
+    /// Forgets the clients that have no active relay and a full bucket, as they are indistinguishable from new clients.
+    pub fn cleanup(&self) {
+        let RelayLimits { rate, burst, .. } = *self.limits.lock().unwrap();
+        let now = Instant::now();
+        self.clients.lock().unwrap().retain(|_, client| {
+            let tokens = client.tokens + now.duration_since(client.last_refill).as_secs_f64() * rate;
+            client.active > 0 || tokens < burst
+        });
+    }
+}
+
+impl Drop for RelayPermit {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap();
+        for key in &self.keys {
+            if let Some(client) = clients.get_mut(key) {
+                client.active = client.active.saturating_sub(1);
+            }
+        }
+        drop(clients);
+        *self.limiter.total.lock().unwrap() -= 1;
+    }
+}
@@ -0,0 +1,81 @@
+use std::{fmt, str::FromStr};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use crate::*;
+
+/// An address to listen on, as in `tcp://[::]:8000` or `unix:///run/mantalon.sock`.
+///
+/// Addresses without a scheme are considered TCP addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenSpec {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix://") {
+            #[cfg(unix)]
+            return match path.is_empty() {
+                true => Err(String::from("missing socket path")),
+                false => Ok(ListenSpec::Unix(PathBuf::from(path))),
+            };
+            #[cfg(not(unix))]
+            return Err(format!("unix sockets are not supported on this platform: {path}"));
+        }
+        let addr = s.strip_prefix("tcp://").unwrap_or(s);
+        addr.parse().map(ListenSpec::Tcp).map_err(|e| format!("invalid socket address {addr}: {e}"))
+    }
+}
+
+impl fmt::Display for ListenSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenSpec::Tcp(addr) => write!(f, "tcp://{addr}"),
+            #[cfg(unix)]
+            ListenSpec::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// A connection accepted by a [`Listener`].
+pub enum Connection {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Listener {
+    pub async fn bind(spec: &ListenSpec) -> std::io::Result<Listener> {
+        match spec {
+            ListenSpec::Tcp(addr) => TcpListener::bind(addr).await.map(Listener::Tcp),
+            #[cfg(unix)]
+            ListenSpec::Unix(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // Remove the socket left behind by a previous run
+                if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                UnixListener::bind(path).map(Listener::Unix)
+            }
+        }
+    }
+
+    pub async fn accept(&self) -> std::io::Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().await.map(|(stream, addr)| Connection::Tcp(stream, addr)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().await.map(|(stream, _)| Connection::Unix(stream)),
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::{OnceLock, RwLock};
+use log::{Log, Metadata, Record};
+
+/// A logger whose filter can be replaced when the configuration is reloaded.
+struct ReloadableLogger(RwLock<env_logger::Logger>);
+
+static LOGGER: OnceLock<&'static ReloadableLogger> = OnceLock::new();
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.0.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.0.read().unwrap().flush()
+    }
+}
+
+/// Builds a logger from `RUST_LOG`, with `filter` taking precedence when set.
+fn build_logger(filter: Option<&str>) -> env_logger::Logger {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(filter) = filter {
+        builder.parse_filters(filter);
+    }
+    builder.build()
+}
+
+/// Installs the logger, filtering records with `filter`, such as `info,mantalon_server=debug`.
+pub fn init_logging(filter: Option<&str>) {
+    let logger = build_logger(filter);
+    log::set_max_level(logger.filter());
+    let logger = LOGGER.get_or_init(|| Box::leak(Box::new(ReloadableLogger(RwLock::new(logger)))));
+    log::set_logger(*logger).expect("the logger is only installed once");
+}
+
+/// Changes the filter of the installed logger.
+pub fn set_log_filter(filter: Option<&str>) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let new_logger = build_logger(filter);
+    log::set_max_level(new_logger.filter());
+    *logger.0.write().unwrap() = new_logger;
+}
@@ -6,77 +6,451 @@ use hyper::{
     server::conn::http1::Builder as HttpBuilder,
     service::service_fn,
     upgrade::Upgraded,
+    header::HeaderValue,
     Method, Request, Response, StatusCode,
 };
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioIo, TokioTimer};
 use log::*;
 use multiaddr::{Multiaddr, Protocol};
 use soketto::connection::Error as SockettoError;
 use soketto::{
     handshake::http::{is_upgrade_request, Server},
-    BoxedError, Data, Receiver, Sender,
+    BoxedError, Receiver, Sender,
 };
 use std::{
-    net::{IpAddr, Ipv4Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
     sync::Arc,
+    time::{Duration, Instant},
 };
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpStream, UdpSocket},
 };
+use tokio_rustls::TlsAcceptor;
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 
+mod admin;
+mod bandwidth;
+mod bans;
+mod config;
+mod destination;
 mod dns;
+mod egress;
+mod errors;
 mod handler;
+mod happy_eyeballs;
+mod limits;
+mod listen;
+mod logging;
+mod metrics;
+mod mux;
+mod origin;
+mod policy;
+mod registry;
 mod relay;
-use {dns::*, handler::*, relay::*};
+mod resolver;
+mod shutdown;
+mod static_files;
+mod ticket;
+mod tls;
+mod websocket;
+use {admin::*, bandwidth::*, bans::*, config::*, destination::*, dns::*, egress::*, errors::*, handler::*, happy_eyeballs::*, limits::*, listen::*, logging::*, metrics::*, mux::*, origin::*, policy::*, registry::*, relay::*, resolver::*, shutdown::*, static_files::*, ticket::*, tls::*, websocket::*};
 
 type FullBody = http_body_util::Full<Bytes>;
 
-/// A proxy server to relay TCP traffic over WebSockets.
+/// A proxy server to relay TCP and UDP traffic over WebSockets.
 #[derive(Parser, Debug)]
 #[command(version, about)]
-struct Args {
-    /// The port to listen on.
+pub struct Args {
+    /// A TOML file of settings, whose keys are the long names of these options, as in `relay_rate = 20`.
+    /// Options given on the command line take precedence. The file is reloaded on SIGHUP and when it changes.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Validates the configuration and exits.
+    #[arg(long)]
+    check_config: bool,
+
+    /// How often to check whether the configuration files changed, in seconds. Zero only reloads them on SIGHUP.
+    #[arg(long, default_value = "2")]
+    config_poll_interval: u64,
+
+    /// Which logs to print, such as `info` or `warn,mantalon_server=debug`. Takes precedence over `RUST_LOG`.
+    #[arg(long, value_name = "FILTER")]
+    log: Option<String>,
+
+    /// The port to listen on, on 127.0.0.1. Ignored when `--listen` is used.
     #[arg(short, long, default_value = "8000")]
     port: u16,
+
+    /// An address to listen on, such as `tcp://0.0.0.0:8000`, `tcp://[::]:8000` or `unix:///run/mantalon.sock`.
+    /// Can be repeated to listen on several addresses.
+    #[arg(short, long = "listen", value_name = "SPEC")]
+    listen: Vec<ListenSpec>,
+
+    /// How long to wait for a TCP connection attempt before racing the next address, in milliseconds.
+    /// Addresses are tried alternating between IPv6 and IPv4, as in Happy Eyeballs (RFC 8305).
+    #[arg(long, default_value = "250")]
+    connect_attempt_delay: u64,
+
+    /// How long a single TCP connection attempt can take, in seconds.
+    #[arg(long, default_value = "10")]
+    connect_attempt_timeout: u64,
+
+    /// How long connecting to a destination can take across all its addresses, in seconds.
+    #[arg(long, default_value = "30")]
+    connect_timeout: u64,
+
+    /// An upstream DNS resolver, such as `udp://192.0.2.1:53`, `tcp://192.0.2.1`, `tls://dns.example` or `https://dns.example/dns-query`.
+    /// Can be repeated, in which case resolvers are tried in order until one answers. The system resolver is used when none is set.
+    #[arg(long = "dns-upstream", value_name = "SPEC")]
+    dns_upstreams: Vec<DnsUpstream>,
+
+    /// How long each upstream DNS resolver has to answer before the next one is tried, in seconds.
+    #[arg(long, default_value = "5")]
+    dns_timeout: u64,
+
+    /// A PEM file of extra CA certificates to trust for `tls://` and `https://` DNS resolvers.
+    #[arg(long)]
+    dns_tls_ca: Option<PathBuf>,
+
+    /// The shortest time DNS answers are cached for, in seconds, whatever their TTL.
+    #[arg(long, default_value = "10")]
+    dns_min_ttl: u64,
+
+    /// The longest time DNS answers are cached for, in seconds, whatever their TTL.
+    #[arg(long, default_value = "3600")]
+    dns_max_ttl: u64,
+
+    /// How long domains that could not be resolved are cached for, in seconds.
+    #[arg(long, default_value = "30")]
+    dns_negative_ttl: u64,
+
+    /// How long DNS answers are cached for when their TTL is unknown, in seconds, before the bounds above apply.
+    #[arg(long, default_value = "300")]
+    dns_default_ttl: u64,
+
+    /// How many domains the DNS cache can hold before evicting the least recently used ones.
+    #[arg(long, default_value = "10000")]
+    dns_cache_size: usize,
+
+    /// A TOML file restricting the destinations clients can connect to.
+    #[arg(long)]
+    policy: Option<PathBuf>,
+
+    /// A TOML file of rules sending TCP connections to some destinations through SOCKS5 or HTTP CONNECT proxies.
+    #[arg(long)]
+    egress: Option<PathBuf>,
+
+    /// How long a UDP relay can go without any datagram before being closed, in seconds.
+    #[arg(long, default_value = "60")]
+    udp_idle_timeout: u64,
+
+    /// How long a TCP relay can go without any byte in either direction before being closed, in seconds. Zero disables the timeout.
+    #[arg(long, default_value = "600")]
+    idle_timeout: u64,
+
+    /// How often to ping clients over their websockets, in seconds. Zero disables pings.
+    #[arg(long, default_value = "30")]
+    ping_interval: u64,
+
+    /// How long clients have to answer a ping before their relays are torn down, in seconds.
+    #[arg(long, default_value = "30")]
+    pong_timeout: u64,
+
+    /// How long a connection to a destination can stay silent before TCP keepalive probes are sent, in seconds. Zero disables them.
+    #[arg(long, default_value = "60")]
+    tcp_keepalive: u64,
+
+    /// How long clients have to send the headers of a request, in seconds.
+    #[arg(long, default_value = "10")]
+    header_read_timeout: u64,
+
+    /// How long TLS and websocket handshakes can take, in seconds.
+    #[arg(long, default_value = "10")]
+    handshake_timeout: u64,
+
+    /// The size of the buffer reading from each TCP destination, which is the largest websocket message relays send, in bytes.
+    #[arg(long, default_value = "100000", value_parser = clap::value_parser!(u64).range(1..))]
+    relay_buffer_size: u64,
+
+    /// The largest websocket message clients can send over TCP relays, in bytes.
+    #[arg(long, default_value = "1048576", value_parser = clap::value_parser!(u64).range(1..))]
+    max_message_size: u64,
+
+    /// The largest datagram that can be relayed over UDP, in bytes.
+    #[arg(long, default_value = "65507")]
+    udp_max_datagram_size: usize,
+
+    /// A PEM file containing the TLS certificate chain. Enables wss:// on TCP listeners when set along with `--tls-key`.
+    /// Both files are reloaded when the process receives SIGHUP.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// A PEM file containing the TLS private key.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// A file containing the secret key used to sign connect tickets.
+    /// When set, clients need a valid ticket to open relays.
+    #[arg(long)]
+    ticket_key_file: Option<PathBuf>,
+
+    /// Prints a ticket for the destinations matching this pattern and exits, as in `/dns/*.wikipedia.org/tcp/443`.
+    #[arg(long, value_name = "PATTERN", requires = "ticket_key_file")]
+    issue_ticket: Option<String>,
+
+    /// How long issued tickets are valid, in seconds.
+    #[arg(long, default_value = "86400")]
+    ticket_lifetime: u64,
+
+    /// Restricts issued tickets to clients connecting from this address.
+    #[arg(long)]
+    ticket_client_ip: Option<IpAddr>,
+
+    /// An origin allowed to open websockets from browsers, such as `https://portal.example.com` or `https://*.example.com`.
+    /// Can be repeated. When set, upgrades from pages of other origins are refused.
+    #[arg(long = "allowed-origin", value_name = "PATTERN")]
+    allowed_origins: Vec<OriginPattern>,
+
+    /// How many new relays a client can open per second, on average.
+    /// Clients are identified by their IP address (or /64 prefix for IPv6) and by their ticket.
+    #[arg(long, default_value = "10")]
+    relay_rate: f64,
+
+    /// How many new relays a client can open at once before `--relay-rate` applies.
+    #[arg(long, default_value = "100")]
+    relay_burst: f64,
+
+    /// How many relays a single client can have open at the same time.
+    #[arg(long, default_value = "256")]
+    max_relays_per_client: usize,
+
+    /// How many relays can be open at the same time, across all clients.
+    #[arg(long, default_value = "4096")]
+    max_relays: usize,
+
+    /// Limits the upload rate of each relay, in bytes per second.
+    #[arg(long)]
+    relay_upload_rate: Option<u64>,
+
+    /// Limits the download rate of each relay, in bytes per second.
+    #[arg(long)]
+    relay_download_rate: Option<u64>,
+
+    /// Limits the upload rate of each client across all its relays, in bytes per second.
+    #[arg(long)]
+    client_upload_rate: Option<u64>,
+
+    /// Limits the download rate of each client across all its relays, in bytes per second.
+    #[arg(long)]
+    client_download_rate: Option<u64>,
+
+    /// How many bytes each client can transfer per day (UTC), uploads and downloads combined.
+    /// Relays exceeding it are closed with code 4509.
+    #[arg(long)]
+    daily_quota: Option<u64>,
+
+    /// How often to log the bandwidth used by each client today, in seconds. Zero disables the report.
+    #[arg(long, default_value = "3600")]
+    usage_report_interval: u64,
+
+    /// Serves Prometheus metrics on `/metrics`, next to `/mantalon-connect`.
+    #[arg(long)]
+    metrics: bool,
+
+    /// A directory to serve the portal from, next to `/mantalon-connect`.
+    /// It holds `index.html`, `sw-bundle.js`, the client in `mantalon/` and the portal configuration in `mantalon/config/`.
+    #[arg(long)]
+    static_root: Option<PathBuf>,
+
+    /// The `Service-Worker-Allowed` header to send with JavaScript files, such as `/` to let a service worker control the whole site.
+    #[arg(long, requires = "static_root")]
+    service_worker_allowed: Option<HeaderValue>,
+
+    /// An address to serve the admin API on, such as `tcp://127.0.0.1:8001` or `unix:///run/mantalon-admin.sock`.
+    /// The API lists and terminates relays, and bans clients and destinations.
+    #[arg(long, value_name = "SPEC", requires = "admin_token_file")]
+    admin_listen: Option<ListenSpec>,
+
+    /// A file containing the token that admin API requests must present in an `Authorization: Bearer` header.
+    #[arg(long)]
+    admin_token_file: Option<PathBuf>,
+
+    /// How long to let relays finish after receiving SIGTERM or SIGINT before closing them, in seconds.
+    #[arg(long, default_value = "30")]
+    shutdown_timeout: u64,
+}
+
+/// State shared by all connections.
+pub struct ServerState {
+    settings: CurrentSettings,
+    dns_cache: DnsCache,
+    limiter: RelayLimiter,
+    bandwidth: Bandwidth,
+    metrics: Metrics,
+    relays: RelayRegistry,
+    bans: Bans,
+    shutdown: Shutdown,
 }
 
 /// Start up a hyper server.
 #[tokio::main]
 async fn main() -> Result<(), BoxedError> {
-    let args = Box::new(Args::parse());
-    let args: &'static Args = Box::leak(args);
-    env_logger::init();
+    let argv: Vec<_> = std::env::args_os().collect();
+    let config = Config::parse(&argv)?;
+    init_logging(config.args.log.as_deref());
 
-    let addr: SocketAddr = ([127, 0, 0, 1], args.port).into();
-    let listener = TcpListener::bind(addr).await?;
-    let dns_cache = DnsCache::default();
+    let (settings, resolver) = Settings::load(config)?;
+    let args = &settings.args;
+    if let (Some(pattern), Some(ticket_key)) = (&args.issue_ticket, &settings.ticket_key) {
+        println!("{}", ticket_key.sign(pattern, now() + args.ticket_lifetime, args.ticket_client_ip));
+        return Ok(());
+    }
+    if args.check_config {
+        if let (Some(cert_path), Some(key_path)) = (&args.tls_cert, &args.tls_key) {
+            tls_acceptor(Arc::new(ReloadableCert::load(cert_path.to_owned(), key_path.to_owned())?))?;
+        }
+        println!("The configuration is valid");
+        return Ok(());
+    }
 
-    info!("Listening on http://{:?}", listener.local_addr().unwrap());
+    let state: &'static ServerState = Box::leak(Box::new(ServerState {
+        dns_cache: DnsCache::new(resolver, args.dns_cache_config()),
+        limiter: RelayLimiter::new(args.relay_rate, args.relay_burst, args.max_relays_per_client, args.max_relays),
+        bandwidth: Bandwidth::new(args.relay_upload_rate, args.relay_download_rate, args.client_upload_rate, args.client_download_rate, args.daily_quota),
+        metrics: Metrics::new(),
+        relays: RelayRegistry::default(),
+        bans: Bans::default(),
+        shutdown: Shutdown::default(),
+        settings: CurrentSettings::new(settings),
+    }));
+    let settings = state.settings();
+    let args = &settings.args;
+    if args.config.is_some() {
+        reload_config_on_change(argv, state)?;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            state.limiter.cleanup();
+            state.bandwidth.cleanup();
+        }
+    });
+    tokio::spawn(async move {
+        loop {
+            // Checked every minute while disabled, so that enabling it in the configuration takes effect
+            let interval = state.settings().args.usage_report_interval;
+            tokio::time::sleep(Duration::from_secs(if interval > 0 { interval } else { 60 })).await;
+            if interval == 0 {
+                continue;
+            }
+            for (key, uploaded, downloaded) in state.bandwidth.usage_report().into_iter().take(20) {
+                info!("Bandwidth used today by {key}: {uploaded} bytes up, {downloaded} bytes down");
+            }
+        }
+    });
+
+    let tls_acceptor = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let cert = Arc::new(ReloadableCert::load(cert_path.to_owned(), key_path.to_owned())?);
+            #[cfg(unix)]
+            reload_cert_on_sighup(Arc::clone(&cert))?;
+            Some(tls_acceptor(cert)?)
+        }
+        _ => None,
+    };
 
+    let listen_specs = match args.listen.is_empty() {
+        true => vec![ListenSpec::Tcp(([127, 0, 0, 1], args.port).into())],
+        false => args.listen.clone(),
+    };
+    for spec in listen_specs {
+        let listener = Listener::bind(&spec).await.map_err(|e| format!("Could not listen on {spec}: {e}"))?;
+        let tls = matches!(spec, ListenSpec::Tcp(_)) && tls_acceptor.is_some();
+        info!("Listening on {spec}{}", if tls { " with TLS" } else { "" });
+        tokio::spawn(accept_loop(listener, tls_acceptor.clone(), state));
+    }
+    if let Some(spec) = &args.admin_listen {
+        let listener = Listener::bind(spec).await.map_err(|e| format!("Could not listen on {spec}: {e}"))?;
+        info!("Serving the admin API on {spec}");
+        tokio::spawn(serve_admin(listener, state));
+    }
+    drop(settings);
+
+    termination_signal().await?;
+    state.shutdown.drain(Duration::from_secs(state.settings().args.shutdown_timeout)).await;
+
+    Ok(())
+}
+
+/// Accepts connections on a listener until the server shuts down.
+async fn accept_loop(listener: Listener, tls_acceptor: Option<TlsAcceptor>, state: &'static ServerState) {
     loop {
-        let stream = match listener.accept().await {
-            Ok((stream, addr)) => {
-                log::info!("Accepting new connection: {addr}");
-                stream
-            }
+        let connection = tokio::select! {
+            connection = listener.accept() => connection,
+            () = state.shutdown.stopping() => return,
+        };
+        let connection = match connection {
+            Ok(connection) => connection,
             Err(e) => {
                 log::error!("Accepting new connection failed: {e}");
                 continue;
             }
         };
 
-        let dns_cache = Arc::clone(&dns_cache);
-        let service = service_fn(move |r| http_handler(r, Arc::clone(&dns_cache), args));
+        let tls_acceptor = tls_acceptor.clone();
         tokio::spawn(async move {
-            let io = TokioIo::new(stream);
-            let conn = HttpBuilder::new().serve_connection(io, service);
-            let conn = conn.with_upgrades(); // Enable upgrades on the connection for the websocket upgrades to work.
-            if let Err(err) = conn.await {
-                error!("HTTP connection failed {err}");
+            match connection {
+                Connection::Tcp(stream, addr) => {
+                    log::info!("Accepting new connection: {addr}");
+                    match tls_acceptor {
+                        Some(tls_acceptor) => match tokio::time::timeout(Duration::from_secs(state.settings().args.handshake_timeout), tls_acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => serve_connection(stream, Some(addr.ip()), state).await,
+                            Ok(Err(e)) => debug!("TLS handshake failed: {e}"),
+                            Err(_) => {
+                                debug!("TLS handshake with {addr} timed out");
+                                state.metrics.timed_out("tls_handshake");
+                            }
+                        },
+                        None => serve_connection(stream, Some(addr.ip()), state).await,
+                    }
+                }
+                #[cfg(unix)]
+                Connection::Unix(stream) => {
+                    log::info!("Accepting new connection on unix socket");
+                    serve_connection(stream, None, state).await
+                }
             }
         });
     }
 }
 
+/// Serves HTTP requests on an accepted connection until it is closed.
+///
+/// `peer_ip` is unknown for unix socket connections.
+async fn serve_connection<IO>(stream: IO, peer_ip: Option<IpAddr>, state: &'static ServerState)
+where
+    IO: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let service = service_fn(move |r| http_handler(r, peer_ip, state));
+    let io = TokioIo::new(stream);
+    let conn = HttpBuilder::new()
+        .timer(TokioTimer::new())
+        .header_read_timeout(Duration::from_secs(state.settings().args.header_read_timeout))
+        .serve_connection(io, service);
+    let conn = conn.with_upgrades(); // Enable upgrades on the connection for the websocket upgrades to work.
+    let mut conn = std::pin::pin!(conn);
+    let result = tokio::select! {
+        result = conn.as_mut() => result,
+        () = state.shutdown.stopping() => {
+            // Let the request in progress complete, then close the connection
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(err) = result {
+        error!("HTTP connection failed {err}");
+    }
+}
@@ -0,0 +1,175 @@
+use std::{collections::BTreeMap, fmt::Write as _, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+use crate::*;
+
+const DURATION_BUCKETS: &[f64] = &[0.1, 1.0, 10.0, 60.0, 300.0, 900.0, 3600.0, 14400.0];
+const DNS_LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Counts observations in cumulative buckets, as Prometheus histograms do.
+struct Histogram {
+    buckets: &'static [f64],
+    inner: Mutex<HistogramInner>,
+}
+
+#[derive(Default)]
+struct HistogramInner {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            buckets,
+            inner: Mutex::new(HistogramInner { counts: vec![0; buckets.len()], ..Default::default() }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let mut inner = self.inner.lock().unwrap();
+        for (bound, count) in self.buckets.iter().zip(inner.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        inner.sum += value;
+        inner.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let inner = self.inner.lock().unwrap();
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        for (bound, count) in self.buckets.iter().zip(inner.counts.iter()) {
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", inner.count);
+        let _ = writeln!(out, "{name}_sum {}", inner.sum);
+        let _ = writeln!(out, "{name}_count {}", inner.count);
+    }
+}
+
+/// Server-wide counters, exposed in the Prometheus text format on `/metrics`.
+pub struct Metrics {
+    relays_active: AtomicU64,
+    relays_total: AtomicU64,
+    relay_duration: Histogram,
+    bytes_uploaded: AtomicU64,
+    bytes_downloaded: AtomicU64,
+    connect_failures: Mutex<BTreeMap<&'static str, u64>>,
+    dns_cache_hits: AtomicU64,
+    dns_cache_misses: AtomicU64,
+    dns_resolution_duration: Histogram,
+    upgrade_rejections: Mutex<BTreeMap<u16, u64>>,
+    timeouts: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Counts a relay as active until dropped, then records its duration.
+pub struct ActiveRelay {
+    metrics: &'static Metrics,
+    start: Instant,
+}
+
+impl Drop for ActiveRelay {
+    fn drop(&mut self) {
+        self.metrics.relays_active.fetch_sub(1, Ordering::Relaxed);
+        self.metrics.relay_duration.observe(self.start.elapsed().as_secs_f64());
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            relays_active: AtomicU64::new(0),
+            relays_total: AtomicU64::new(0),
+            relay_duration: Histogram::new(DURATION_BUCKETS),
+            bytes_uploaded: AtomicU64::new(0),
+            bytes_downloaded: AtomicU64::new(0),
+            connect_failures: Mutex::new(BTreeMap::new()),
+            dns_cache_hits: AtomicU64::new(0),
+            dns_cache_misses: AtomicU64::new(0),
+            dns_resolution_duration: Histogram::new(DNS_LATENCY_BUCKETS),
+            upgrade_rejections: Mutex::new(BTreeMap::new()),
+            timeouts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn relay_started(&'static self) -> ActiveRelay {
+        self.relays_active.fetch_add(1, Ordering::Relaxed);
+        self.relays_total.fetch_add(1, Ordering::Relaxed);
+        ActiveRelay { metrics: self, start: Instant::now() }
+    }
+
+    pub fn uploaded(&self, n: usize) {
+        self.bytes_uploaded.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn downloaded(&self, n: usize) {
+        self.bytes_downloaded.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a relay that could not reach its destination, such as `resolve`, `policy`, `refused` or `timeout`.
+    pub fn connect_failed(&self, reason: &'static str) {
+        *self.connect_failures.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn dns_cache_hit(&self) {
+        self.dns_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a DNS cache miss along with the time the resolution took.
+    pub fn dns_resolved(&self, duration: Duration) {
+        self.dns_cache_misses.fetch_add(1, Ordering::Relaxed);
+        self.dns_resolution_duration.observe(duration.as_secs_f64());
+    }
+
+    pub fn upgrade_rejected(&self, status: StatusCode) {
+        *self.upgrade_rejections.lock().unwrap().entry(status.as_u16()).or_default() += 1;
+    }
+
+    /// Counts a connection or relay closed because it took too long, such as `idle`, `handshake` or `tls_handshake`.
+    pub fn timed_out(&self, kind: &'static str) {
+        *self.timeouts.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}");
+        };
+        counter("mantalon_relays_active", "gauge", "Relays currently open.", self.relays_active.load(Ordering::Relaxed));
+        counter("mantalon_relays_total", "counter", "Relays opened since the server started.", self.relays_total.load(Ordering::Relaxed));
+        counter("mantalon_uploaded_bytes_total", "counter", "Bytes relayed from clients to destinations.", self.bytes_uploaded.load(Ordering::Relaxed));
+        counter("mantalon_downloaded_bytes_total", "counter", "Bytes relayed from destinations to clients.", self.bytes_downloaded.load(Ordering::Relaxed));
+        counter("mantalon_dns_cache_hits_total", "counter", "Domain resolutions answered from the cache.", self.dns_cache_hits.load(Ordering::Relaxed));
+        counter("mantalon_dns_cache_misses_total", "counter", "Domain resolutions sent to the resolver.", self.dns_cache_misses.load(Ordering::Relaxed));
+
+        self.relay_duration.render(&mut out, "mantalon_relay_duration_seconds", "How long relays stayed open.");
+        self.dns_resolution_duration.render(&mut out, "mantalon_dns_resolution_duration_seconds", "How long resolving domains not in the cache took.");
+
+        let _ = writeln!(out, "# HELP mantalon_connect_failures_total Relays that could not reach their destination, by reason.\n# TYPE mantalon_connect_failures_total counter");
+        for (reason, count) in self.connect_failures.lock().unwrap().iter() {
+            let _ = writeln!(out, "mantalon_connect_failures_total{{reason=\"{reason}\"}} {count}");
+        }
+        let _ = writeln!(out, "# HELP mantalon_upgrade_rejections_total Connect requests that were not upgraded to websockets, by response status.\n# TYPE mantalon_upgrade_rejections_total counter");
+        for (status, count) in self.upgrade_rejections.lock().unwrap().iter() {
+            let _ = writeln!(out, "mantalon_upgrade_rejections_total{{status=\"{status}\"}} {count}");
+        }
+        let _ = writeln!(out, "# HELP mantalon_timeouts_total Connections and relays closed for taking too long, by kind.\n# TYPE mantalon_timeouts_total counter");
+        for (kind, count) in self.timeouts.lock().unwrap().iter() {
+            let _ = writeln!(out, "mantalon_timeouts_total{{kind=\"{kind}\"}} {count}");
+        }
+        out
+    }
+}
+
+/// Classifies an error that prevented connecting to a destination.
+pub fn connect_failure_reason(e: &std::io::Error) -> &'static str {
+    use std::io::ErrorKind;
+    match e.kind() {
+        ErrorKind::ConnectionRefused => "refused",
+        ErrorKind::TimedOut => "timeout",
+        ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => "reset",
+        ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => "unreachable",
+        _ => "other",
+    }
+}
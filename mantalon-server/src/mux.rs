@@ -0,0 +1,382 @@
+//! Multiplexes many relays over one websocket, so that clients connect to the server once for all their destinations.
+//!
+//! Each binary message of the websocket is a frame: a type byte, a big-endian `u32` stream id chosen by the client, and a payload.
+//!
+//! | Type | Frame    | Payload |
+//! |------|----------|---------|
+//! | 0    | `OPEN`   | The multiaddr of the destination, as text. Sent by the client only |
+//! | 1    | `DATA`   | Bytes of the stream, no more than the window of the receiver allows |
+//! | 2    | `WINDOW` | A big-endian `u32` of bytes the sender is ready to receive in addition to its window |
+//! | 3    | `CLOSE`  | A big-endian `u16` close code followed by a reason. No other frame follows for the stream |
+//! | 4    | `FIN`    | Empty. The sender will not send data on the stream anymore, but still receives it |
+//!
+//! Each side starts with a window of [`INITIAL_WINDOW`] bytes per stream.
+//! A stream ends once both sides sent `FIN` and the server wrote everything to the destination, or as soon as either side sends `CLOSE`.
+
+use std::{collections::HashMap, sync::{atomic::{AtomicU32, Ordering}, Mutex}};
+use tokio::{sync::{mpsc, oneshot, Semaphore}, task::JoinSet};
+use crate::*;
+
+pub const FRAME_OPEN: u8 = 0;
+pub const FRAME_DATA: u8 = 1;
+pub const FRAME_WINDOW: u8 = 2;
+pub const FRAME_CLOSE: u8 = 3;
+pub const FRAME_FIN: u8 = 4;
+
+/// How many bytes each side can send on a stream before the other grants it more.
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// The largest payload of a data frame.
+pub const MAX_DATA_SIZE: usize = 16 * 1024;
+
+/// The client broke the multiplexing protocol.
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+fn frame(kind: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&stream_id.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn close_frame(stream_id: u32, code: u16, reason: &str) -> Vec<u8> {
+    frame(FRAME_CLOSE, stream_id, &[&code.to_be_bytes(), reason.as_bytes()].concat())
+}
+
+/// What the session knows of one of its streams.
+struct MuxStream {
+    /// Data received from the client, to write to the destination, until the client sends `FIN`.
+    to_destination: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Bytes received from the client that have not been written to the destination yet.
+    unacknowledged: Arc<AtomicU32>,
+    /// Bytes the client is ready to receive.
+    send_window: Arc<Semaphore>,
+    /// Dropped with the stream when the client closes it, which ends the stream task.
+    _closed: oneshot::Sender<()>,
+}
+
+/// Forgets a stream, unless its id was already reused by a newer stream the client opened after closing it.
+///
+/// Returns whether the stream was still known.
+fn forget_stream(streams: &mut HashMap<u32, MuxStream>, stream_id: u32, send_window: &Arc<Semaphore>) -> bool {
+    match streams.get(&stream_id) {
+        Some(stream) if Arc::ptr_eq(&stream.send_window, send_window) => {
+            streams.remove(&stream_id);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// What the task of a stream shares with the session.
+struct StreamChannels {
+    from_client: mpsc::UnboundedReceiver<Vec<u8>>,
+    client_closed: oneshot::Receiver<()>,
+    unacknowledged: Arc<AtomicU32>,
+    send_window: Arc<Semaphore>,
+    frames: mpsc::Sender<Vec<u8>>,
+}
+
+/// A websocket carrying multiplexed streams, each of which is a relay of its own.
+pub struct MuxSession {
+    client_ip: Option<IpAddr>,
+    ticket: Option<String>,
+    streams: Mutex<HashMap<u32, MuxStream>>,
+    state: &'static ServerState,
+}
+
+impl MuxSession {
+    pub fn new(client_ip: Option<IpAddr>, ticket: Option<String>, state: &'static ServerState) -> Arc<MuxSession> {
+        Arc::new(MuxSession { client_ip, ticket, streams: Mutex::new(HashMap::new()), state })
+    }
+
+    /// Completes the websocket handshake and serves streams until the websocket is closed.
+    pub async fn run(self: Arc<Self>, server: Server, req: Request<Incoming>) {
+        let (mut sender, mut receiver, closer) = match tokio::time::timeout(Duration::from_secs(self.state.settings().args.handshake_timeout), handshake(server, req, Some(5 + MAX_DATA_SIZE), self.state.settings().args.keepalive())).await {
+            Ok(Ok(handshake)) => handshake,
+            Ok(Err(e)) => {
+                error!("Could not complete handshake: {e}");
+                return;
+            }
+            Err(_) => {
+                debug!("Websocket handshake timed out");
+                self.state.metrics.timed_out("handshake");
+                return;
+            }
+        };
+        debug!("Multiplexed session now operational");
+        let heartbeat = receiver.heartbeat();
+
+        // Frames of all streams are sent by a single task
+        let (frames, mut outgoing) = mpsc::channel::<Vec<u8>>(64);
+        let write = async move {
+            loop {
+                tokio::select! {
+                    frame = outgoing.recv() => match frame {
+                        Some(frame) => {
+                            sender.send_binary(&frame).await?;
+                            sender.flush().await?;
+                        }
+                        None => break,
+                    },
+                    () = sender.ping_due() => sender.send_ping().await?,
+                }
+            }
+            Ok::<(), SockettoError>(())
+        };
+
+        // Streams are aborted when the read loop ends
+        let session = Arc::clone(&self);
+        let read = async move {
+            let mut tasks = JoinSet::new();
+            let mut message = Vec::new();
+            loop {
+                message.clear();
+                match receiver.receive_data(&mut message).await {
+                    Ok(_) => (),
+                    Err(SockettoError::Closed) => return None,
+                    Err(SockettoError::MessageTooLarge { .. }) => return Some((CLOSE_PROTOCOL_ERROR, "Frame too large")),
+                    Err(e) => {
+                        error!("Websocket connection error: {e}");
+                        return None;
+                    }
+                }
+                if let Err(reason) = session.handle_frame(&message, &frames, &mut tasks) {
+                    debug!("Closing multiplexed session: {reason}");
+                    return Some((CLOSE_PROTOCOL_ERROR, reason));
+                }
+            }
+        };
+
+        let close = tokio::select! {
+            close = read => close,
+            result = write => {
+                if let Err(e) = result {
+                    error!("Websocket connection error: {e}");
+                }
+                None
+            }
+            () = heartbeat.dead() => {
+                debug!("Dropping multiplexed session as the client stopped answering pings");
+                self.state.metrics.timed_out("pong");
+                None
+            }
+            () = self.state.shutdown.going_away() => Some((CLOSE_GOING_AWAY, "Server shutting down")),
+        };
+        if let Some((code, reason)) = close {
+            closer.close(code, reason).await;
+        }
+    }
+
+    fn handle_frame(self: &Arc<Self>, message: &[u8], frames: &mpsc::Sender<Vec<u8>>, tasks: &mut JoinSet<()>) -> Result<(), &'static str> {
+        let [kind, a, b, c, d, payload @ ..] = message else {
+            return Err("Truncated frame");
+        };
+        let stream_id = u32::from_be_bytes([*a, *b, *c, *d]);
+        let mut streams = self.streams.lock().unwrap();
+        match *kind {
+            FRAME_OPEN => {
+                if streams.contains_key(&stream_id) {
+                    return Err("Stream id already in use");
+                }
+                let addr = String::from_utf8_lossy(payload).parse::<Multiaddr>();
+                let (to_destination, from_client) = mpsc::unbounded_channel();
+                let (closed, client_closed) = oneshot::channel();
+                let stream = MuxStream {
+                    to_destination: Some(to_destination),
+                    unacknowledged: Arc::new(AtomicU32::new(0)),
+                    send_window: Arc::new(Semaphore::new(INITIAL_WINDOW as usize)),
+                    _closed: closed,
+                };
+                let (unacknowledged, send_window) = (Arc::clone(&stream.unacknowledged), Arc::clone(&stream.send_window));
+                streams.insert(stream_id, stream);
+                let channels = StreamChannels { from_client, client_closed, unacknowledged, send_window, frames: frames.clone() };
+                tasks.spawn(Arc::clone(self).run_stream(stream_id, addr, channels));
+            }
+            FRAME_DATA => {
+                // Data can still arrive for streams we closed
+                let Some(stream) = streams.get(&stream_id) else {
+                    return Ok(());
+                };
+                let Some(to_destination) = &stream.to_destination else {
+                    return Err("Data after the end of the stream");
+                };
+                let len = payload.len() as u32;
+                if stream.unacknowledged.fetch_add(len, Ordering::Relaxed) + len > INITIAL_WINDOW {
+                    return Err("Window exceeded");
+                }
+                let _ = to_destination.send(payload.to_vec());
+            }
+            FRAME_WINDOW => {
+                let Ok(increment) = <[u8; 4]>::try_from(payload).map(u32::from_be_bytes) else {
+                    return Err("Invalid window update");
+                };
+                let Some(stream) = streams.get(&stream_id) else {
+                    return Ok(());
+                };
+                if stream.send_window.available_permits() + increment as usize > u32::MAX as usize {
+                    return Err("Window too large");
+                }
+                stream.send_window.add_permits(increment as usize);
+            }
+            FRAME_CLOSE => {
+                // Closing the window tells the stream it was closed rather than ended by the client
+                if let Some(stream) = streams.remove(&stream_id) {
+                    stream.send_window.close();
+                }
+            }
+            FRAME_FIN => {
+                // Dropping the channel shuts down the destination's side once the data already received is written
+                if let Some(stream) = streams.get_mut(&stream_id) {
+                    stream.to_destination = None;
+                }
+            }
+            _ => return Err("Unknown frame type"),
+        }
+        Ok(())
+    }
+
+    async fn run_stream(self: Arc<Self>, stream_id: u32, addr: Result<Multiaddr, multiaddr::Error>, channels: StreamChannels) {
+        let StreamChannels { mut from_client, client_closed, unacknowledged, send_window, frames } = channels;
+
+        // Each stream is opened with the configuration current at the time, as separate relays would be
+        let (state, settings) = (self.state, self.state.settings());
+        let args = &settings.args;
+        let relay = match addr {
+            Ok(addr) => open_relay(addr, self.client_ip, self.ticket.clone(), state, &settings).await,
+            Err(e) => Err(MantalonError::InvalidAddr(e)),
+        };
+        let (addr, remote_addr, mut reader, mut writer, permit, account) = match relay {
+            Ok(PreparedRelay { addr, remote_addr, transport: Transport::Stream(reader, writer), permit, account }) => (addr, remote_addr, reader, writer, permit, account),
+            Ok(PreparedRelay { addr, transport: Transport::Datagram(_), .. }) => {
+                let e = MantalonError::UnsupportedProtocol(format!("{addr} is a UDP destination, which cannot be multiplexed"));
+                self.close_stream(stream_id, &send_window, &frames, e.close_code(), &e.to_string()).await;
+                return;
+            }
+            Err(e) => {
+                debug!("Could not open stream {stream_id}: {e}");
+                self.close_stream(stream_id, &send_window, &frames, e.close_code(), &e.to_string()).await;
+                return;
+            }
+        };
+        debug!("Stream {stream_id} now operational to {addr}");
+
+        let _permit = permit;
+        let meter = Arc::new(state.bandwidth.meter(account.as_ref(), &state.metrics));
+        let registered = state.relays.register(&addr, remote_addr, self.client_ip, true, &meter);
+        let _active = state.metrics.relay_started();
+        // Each direction ends on its own, with an error when the stream must be closed, carrying the close frame to send if any
+        let upload = async {
+            // The channel closes when the client ends its side of the stream, or closes the stream
+            while let Some(data) = from_client.recv().await {
+                if meter.upload(data.len()).await.is_err() {
+                    return Err(Some((CLOSE_QUOTA_EXCEEDED, String::from("Daily quota exceeded"))));
+                }
+                if let Err(e) = async { writer.write_all(&data).await?; writer.flush().await }.await {
+                    return Err(Some((CLOSE_CONNECT_FAILED, e.to_string())));
+                }
+                unacknowledged.fetch_sub(data.len() as u32, Ordering::Relaxed);
+                let _ = frames.send(frame(FRAME_WINDOW, stream_id, &(data.len() as u32).to_be_bytes())).await;
+            }
+            if send_window.is_closed() {
+                return Err(None);
+            }
+
+            // Tell the destination that the client will not send anything more
+            if let Err(e) = writer.shutdown().await {
+                debug!("Could not shut down stream {stream_id}: {e}");
+            }
+            Ok(())
+        };
+        let download = async {
+            let mut buffer = vec![0; MAX_DATA_SIZE];
+            loop {
+                let n = match reader.read(&mut buffer).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(e) => return Err(Some((CLOSE_CONNECT_FAILED, e.to_string()))),
+                };
+                // Wait for the client to make room for the data
+                match send_window.acquire_many(n as u32).await {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => return Err(None),
+                }
+                if meter.download(n).await.is_err() {
+                    return Err(Some((CLOSE_QUOTA_EXCEEDED, String::from("Daily quota exceeded"))));
+                }
+                if frames.send(frame(FRAME_DATA, stream_id, &buffer[..n])).await.is_err() {
+                    return Err(None);
+                }
+            }
+
+            // Tell the client that the destination will not send anything more
+            match frames.send(frame(FRAME_FIN, stream_id, &[])).await {
+                Ok(()) => Ok(()),
+                Err(_) => Err(None),
+            }
+        };
+
+        let close = tokio::select! {
+            result = async { tokio::try_join!(upload, download) } => result.err().flatten(),
+            _ = client_closed => None,
+            () = meter.idle(Duration::from_secs(args.idle_timeout)) => {
+                debug!("Closing stream {stream_id} to {addr} after {}s without traffic", args.idle_timeout);
+                state.metrics.timed_out("idle");
+                Some((CLOSE_IDLE_TIMEOUT, String::from("Idle timeout")))
+            }
+            () = registered.killed() => {
+                info!("Terminating stream {stream_id} to {addr} (relay {}) at the request of an administrator", registered.id());
+                Some((CLOSE_TERMINATED, String::from("Terminated by an administrator")))
+            }
+        };
+        match close {
+            Some((code, reason)) => self.close_stream(stream_id, &send_window, &frames, code, &reason).await,
+            None => {
+                forget_stream(&mut self.streams.lock().unwrap(), stream_id, &send_window);
+                debug!("Stream {stream_id} to {addr} ended");
+            }
+        }
+    }
+
+    /// Forgets a stream and tells the client it is closed.
+    async fn close_stream(&self, stream_id: u32, send_window: &Arc<Semaphore>, frames: &mpsc::Sender<Vec<u8>>, code: u16, reason: &str) {
+        let known = forget_stream(&mut self.streams.lock().unwrap(), stream_id, send_window);
+        if known {
+            let _ = frames.send(close_frame(stream_id, code, reason)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(streams: &mut HashMap<u32, MuxStream>, stream_id: u32) -> Arc<Semaphore> {
+        let (to_destination, _) = mpsc::unbounded_channel();
+        let (closed, _) = oneshot::channel();
+        let send_window = Arc::new(Semaphore::new(INITIAL_WINDOW as usize));
+        streams.insert(stream_id, MuxStream {
+            to_destination: Some(to_destination),
+            unacknowledged: Arc::new(AtomicU32::new(0)),
+            send_window: Arc::clone(&send_window),
+            _closed: closed,
+        });
+        send_window
+    }
+
+    #[test]
+    fn reused_stream_id_survives_the_end_of_the_closed_stream() {
+        let mut streams = HashMap::new();
+        let first = open(&mut streams, 7);
+
+        // The client closes the stream and opens it again before the first task winds down
+        streams.remove(&7).unwrap().send_window.close();
+        let second = open(&mut streams, 7);
+
+        assert!(!forget_stream(&mut streams, 7, &first));
+        assert!(Arc::ptr_eq(&streams[&7].send_window, &second));
+        assert!(forget_stream(&mut streams, 7, &second));
+        assert!(streams.is_empty());
+    }
+}
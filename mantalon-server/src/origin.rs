@@ -0,0 +1,118 @@
+use std::str::FromStr;
+use hyper::Uri;
+use crate::*;
+
+/// An origin allowed to open websockets, such as `https://portal.example.com`.
+///
+/// `*` stands for any sequence of characters, as in `https://*.example.com`.
+#[derive(Debug, Clone)]
+pub struct OriginPattern(String);
+
+impl FromStr for OriginPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim_end_matches('/').to_ascii_lowercase();
+        match pattern.split_once("://") {
+            Some((scheme, host)) if !scheme.is_empty() && !host.is_empty() && !host.contains(['/', '@']) => {
+                Ok(OriginPattern(format!("{scheme}://{}", strip_default_port(scheme, host))))
+            }
+            _ => Err(format!("invalid origin {s}, expected something like https://portal.example.com or https://*.example.com")),
+        }
+    }
+}
+
+/// Removes the port from `host` when it is the default one of `scheme`, as browsers do in origins.
+fn strip_default_port<'a>(scheme: &str, host: &'a str) -> &'a str {
+    let default_port = match scheme {
+        "http" | "ws" => ":80",
+        "https" | "wss" => ":443",
+        _ => return host,
+    };
+    host.strip_suffix(default_port).unwrap_or(host)
+}
+
+/// Returns the `scheme://host[:port]` form of an `Origin` header, or `None` if it is not a valid origin, such as `null`.
+fn normalize_origin(origin: &str) -> Option<String> {
+    let uri = origin.parse::<Uri>().ok()?;
+    let (scheme, authority) = (uri.scheme_str()?, uri.authority()?);
+    if authority.as_str().contains('@') || uri.path() != "/" || uri.query().is_some() || origin.ends_with('/') {
+        return None;
+    }
+    let (scheme, authority) = (scheme.to_ascii_lowercase(), authority.as_str().to_ascii_lowercase());
+    Some(format!("{scheme}://{}", strip_default_port(&scheme, &authority)))
+}
+
+/// Checks the `Origin` header of a request against the allowed origins, returning the origin if it is refused.
+///
+/// Every origin is allowed when the list is empty. Requests without an `Origin` header, which browsers always send
+/// with websockets, come from other programs and are let through.
+pub fn check_origin<B>(req: &Request<B>, allowed_origins: &[OriginPattern]) -> Result<(), String> {
+    let Some(origin) = req.headers().get("origin") else {
+        return Ok(());
+    };
+    if allowed_origins.is_empty() {
+        return Ok(());
+    }
+    let origin_str = String::from_utf8_lossy(origin.as_bytes());
+    let allowed = normalize_origin(&origin_str).is_some_and(|origin| allowed_origins.iter().any(|pattern| wildcard_match(&pattern.0, &origin)));
+    match allowed {
+        true => Ok(()),
+        false => Err(origin_str.into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(origin: Option<&str>, allowed_origins: &[&str]) -> bool {
+        let allowed_origins: Vec<OriginPattern> = allowed_origins.iter().map(|pattern| pattern.parse().unwrap()).collect();
+        let mut req = Request::builder();
+        if let Some(origin) = origin {
+            req = req.header("Origin", origin);
+        }
+        check_origin(&req.body(()).unwrap(), &allowed_origins).is_ok()
+    }
+
+    #[test]
+    fn origins_are_checked_against_patterns() {
+        let cases: &[(Option<&str>, &[&str], bool)] = &[
+            (Some("https://a.example"), &["https://a.example"], true),
+            (Some("https://a.example:443"), &["https://a.example"], true),
+            (Some("https://a.example"), &["https://a.example:443"], true),
+            (Some("http://a.example:80"), &["http://a.example"], true),
+            (Some("https://a.example:8443"), &["https://a.example"], false),
+            (Some("https://a.example:8443"), &["https://a.example:8443"], true),
+            (Some("https://a.example:80"), &["https://a.example"], false),
+            (Some("http://a.example"), &["https://a.example"], false),
+            (Some("https://a.example"), &["https://a.example/"], true),
+            (Some("https://a.example/"), &["https://a.example"], false),
+            (Some("https://a.example/path"), &["https://a.example"], false),
+            (Some("HTTPS://A.Example"), &["https://a.example"], true),
+            (Some("https://a.example"), &["HTTPS://A.EXAMPLE"], true),
+            (Some("https://user@a.example"), &["https://*a.example"], false),
+            (Some("https://www.example.org"), &["https://*.example.org"], true),
+            (Some("https://a.b.example.org"), &["https://*.example.org"], true),
+            (Some("https://example.org"), &["https://*.example.org"], false),
+            (Some("https://evil.example.org.attacker.com"), &["https://*.example.org"], false),
+            (Some("https://evilexample.org"), &["https://*.example.org"], false),
+            (Some("https://b.example"), &["https://a.example", "https://b.example"], true),
+            (Some("null"), &["https://a.example"], false),
+            (Some("null"), &["*://*"], false),
+            (Some("null"), &[], true),
+            (Some("https://a.example"), &[], true),
+            (None, &["https://a.example"], true),
+        ];
+        for (origin, allowed_origins, expected) in cases {
+            assert_eq!(check(*origin, allowed_origins), *expected, "{origin:?} against {allowed_origins:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_patterns_are_refused() {
+        for pattern in ["a.example", "https://", "://a.example", "https://a.example/path", "https://user@a.example"] {
+            assert!(pattern.parse::<OriginPattern>().is_err(), "{pattern:?}");
+        }
+    }
+}
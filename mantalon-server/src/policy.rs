@@ -0,0 +1,535 @@
+use std::{fmt, net::{Ipv4Addr, Ipv6Addr}, ops::RangeInclusive, path::Path};
+use ipnet::IpNet;
+use serde::Deserialize;
+use crate::*;
+
+/// Restrictions on the destinations clients can reach through the proxy.
+///
+/// Denials always take precedence over allowances.
+/// When any of the `allowed_domains` or `allowed_ips` lists is non-empty, a destination must be allowed by one of them.
+/// Private, loopback and other special-purpose addresses are refused unless listed in `allowed_ips`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Policy {
+    /// Lets clients reach private, loopback and other special-purpose addresses without listing them in `allowed_ips`.
+    allow_special_purpose_ips: bool,
+    /// Domains that can be reached, along with all the addresses they resolve to.
+    /// Patterns can contain `*` wildcards, as in `*.wikipedia.org`.
+    allowed_domains: Vec<DomainPattern>,
+    /// Domains that can never be reached.
+    denied_domains: Vec<DomainPattern>,
+    /// Addresses or CIDR ranges that can be reached, as in `192.0.2.0/24` or `2001:db8::1`.
+    allowed_ips: Vec<IpRule>,
+    /// Addresses or CIDR ranges that can never be reached.
+    denied_ips: Vec<IpRule>,
+    /// Ports or port ranges that can be reached, as in `443` or `"8000-8100"`. An empty list allows every port.
+    allowed_ports: Vec<PortRule>,
+    /// Ports or port ranges that can never be reached.
+    denied_ports: Vec<PortRule>,
+}
+
+#[derive(Debug)]
+pub enum PolicyError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Io(e) => write!(f, "Could not read policy file: {e}"),
+            PolicyError::Parse(e) => write!(f, "Invalid policy file: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+/// The reason a destination was refused.
+#[derive(Debug)]
+pub enum PolicyViolation {
+    DeniedDomain(String),
+    DomainNotAllowed(String),
+    DeniedPort(u16),
+    PortNotAllowed(u16),
+    NoAllowedIp(Vec<IpAddr>),
+    SpecialPurposeIp(IpAddr, &'static str),
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::DeniedDomain(domain) => write!(f, "domain {domain} is denied"),
+            PolicyViolation::DomainNotAllowed(domain) => write!(f, "domain {domain} is not in the allowed list"),
+            PolicyViolation::DeniedPort(port) => write!(f, "port {port} is denied"),
+            PolicyViolation::PortNotAllowed(port) => write!(f, "port {port} is not in the allowed list"),
+            PolicyViolation::NoAllowedIp(ips) => write!(f, "none of the addresses {ips:?} are allowed"),
+            PolicyViolation::SpecialPurposeIp(ip, range) => write!(f, "address {ip} is in the {range} range"),
+        }
+    }
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Policy, PolicyError> {
+        let content = std::fs::read_to_string(path).map_err(PolicyError::Io)?;
+        toml::from_str(&content).map_err(PolicyError::Parse)
+    }
+
+    /// Reads the policy from a table of the configuration file.
+    pub fn from_table(table: toml::Table) -> Result<Policy, PolicyError> {
+        toml::Value::Table(table).try_into().map_err(PolicyError::Parse)
+    }
+
+    fn has_allowlist(&self) -> bool {
+        !self.allowed_domains.is_empty() || !self.allowed_ips.is_empty()
+    }
+
+    /// Checks the parts of a destination known before resolution.
+    ///
+    /// Returns whether the domain is explicitly allowed, in which case its addresses don't need to be in `allowed_ips`.
+    pub fn check_destination(&self, domain: Option<&str>, port: u16) -> Result<bool, PolicyViolation> {
+        if self.denied_ports.iter().any(|rule| rule.matches(port)) {
+            return Err(PolicyViolation::DeniedPort(port));
+        }
+        if !self.allowed_ports.is_empty() && !self.allowed_ports.iter().any(|rule| rule.matches(port)) {
+            return Err(PolicyViolation::PortNotAllowed(port));
+        }
+
+        let Some(domain) = domain else {
+            return Ok(false);
+        };
+        let domain = normalize_domain(domain);
+        if self.denied_domains.iter().any(|pattern| pattern.matches(&domain)) {
+            return Err(PolicyViolation::DeniedDomain(domain));
+        }
+        let allowed = self.allowed_domains.iter().any(|pattern| pattern.matches(&domain));
+        if self.allowed_ips.is_empty() && !self.allowed_domains.is_empty() && !allowed {
+            return Err(PolicyViolation::DomainNotAllowed(domain));
+        }
+
+        Ok(allowed)
+    }
+
+    /// Checks a domain whose addresses are unknown because an egress proxy resolves it.
+    ///
+    /// Unless the domain is explicitly allowed, it is refused when only some addresses can be reached.
+    pub fn check_unresolved_domain(&self, domain: &str, domain_allowed: bool) -> Result<(), PolicyViolation> {
+        match domain_allowed || !self.has_allowlist() {
+            true => Ok(()),
+            false => Err(PolicyViolation::DomainNotAllowed(normalize_domain(domain))),
+        }
+    }
+
+    /// Removes the addresses that cannot be reached from `ips`.
+    ///
+    /// Fails if addresses were provided but none of them can be reached.
+    pub fn filter_ips(&self, ips: &mut Vec<IpAddr>, domain_allowed: bool) -> Result<(), PolicyViolation> {
+        let original = ips.clone();
+        let mut special_purpose = None;
+        ips.retain(|ip| {
+            let canonical = ip.to_canonical();
+            if self.denied_ips.iter().any(|rule| rule.0.contains(&canonical)) {
+                return false;
+            }
+            let explicitly_allowed = self.allowed_ips.iter().any(|rule| rule.0.contains(&canonical));
+            if !self.allow_special_purpose_ips && !explicitly_allowed {
+                if let Some(range) = special_purpose_range(*ip) {
+                    debug!("Ignoring address {ip} in the {range} range");
+                    special_purpose = Some(PolicyViolation::SpecialPurposeIp(*ip, range));
+                    return false;
+                }
+            }
+            domain_allowed || !self.has_allowlist() || explicitly_allowed
+        });
+
+        if ips.is_empty() && !original.is_empty() {
+            return Err(special_purpose.unwrap_or(PolicyViolation::NoAllowedIp(original)));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the name of the special-purpose range `ip` belongs to, if any.
+///
+/// Addresses embedding an IPv4 address (IPv4-mapped, NAT64 and 6to4) are classified according to the embedded address.
+pub fn special_purpose_range(ip: IpAddr) -> Option<&'static str> {
+    match ip {
+        IpAddr::V4(ip) => special_purpose_range_v4(ip),
+        IpAddr::V6(ip) => special_purpose_range_v6(ip),
+    }
+}
+
+fn special_purpose_range_v4(ip: Ipv4Addr) -> Option<&'static str> {
+    let [a, b, c, _] = ip.octets();
+    match (a, b, c) {
+        (0, _, _) => Some("\"this network\""),
+        (10, _, _) | (172, 16..=31, _) | (192, 168, _) => Some("private"),
+        (100, 64..=127, _) => Some("shared address space (CGNAT)"),
+        (127, _, _) => Some("loopback"),
+        (169, 254, _) => Some("link-local"),
+        (192, 0, 0) => Some("IETF protocol assignments"),
+        (192, 0, 2) | (198, 51, 100) | (203, 0, 113) => Some("documentation"),
+        (192, 88, 99) => Some("6to4 relay anycast"),
+        (198, 18..=19, _) => Some("benchmarking"),
+        (224..=239, _, _) => Some("multicast"),
+        (240..=255, _, _) => Some("reserved"),
+        _ => None,
+    }
+}
+
+fn special_purpose_range_v6(ip: Ipv6Addr) -> Option<&'static str> {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return special_purpose_range_v4(ip);
+    }
+    let segments = ip.segments();
+    match segments {
+        [0, 0, 0, 0, 0, 0, 0, 0] => Some("unspecified"),
+        [0, 0, 0, 0, 0, 0, 0, 1] => Some("loopback"),
+        [0, 0, 0, 0, 0, 0, _, _] => Some("IPv4-compatible"),
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => special_purpose_range_v4(embedded_ipv4(&segments[6..8])),
+        [0x64, 0xff9b, 1, ..] => Some("local-use IPv4/IPv6 translation"),
+        [0x100, 0, 0, 0, ..] => Some("discard-only"),
+        [0x2001, 0..=0x1ff, ..] => Some("IETF protocol assignments"),
+        [0x2001, 0xdb8, ..] | [0x3fff, 0..=0xfff, ..] => Some("documentation"),
+        [0x2002, ..] => special_purpose_range_v4(embedded_ipv4(&segments[1..3])),
+        [0xfc00..=0xfdff, ..] => Some("unique local"),
+        [0xfe80..=0xfebf, ..] => Some("link-local"),
+        [0xfec0..=0xfeff, ..] => Some("site-local"),
+        [0xff00..=0xffff, ..] => Some("multicast"),
+        _ => None,
+    }
+}
+
+fn embedded_ipv4(segments: &[u16]) -> Ipv4Addr {
+    Ipv4Addr::from((u32::from(segments[0]) << 16) | u32::from(segments[1]))
+}
+
+pub fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Matches `text` against a `pattern` in which `*` stands for any sequence of characters.
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty(); // There was no wildcard
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(from = "String")]
+pub struct DomainPattern(String);
+
+impl From<String> for DomainPattern {
+    fn from(pattern: String) -> Self {
+        DomainPattern(normalize_domain(&pattern))
+    }
+}
+
+impl DomainPattern {
+    /// Matches a domain normalized with [`normalize_domain`].
+    pub fn matches(&self, domain: &str) -> bool {
+        wildcard_match(&self.0, domain)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRule(IpNet);
+
+impl TryFrom<String> for IpRule {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Ok(net) = value.parse::<IpNet>() {
+            return Ok(IpRule(net));
+        }
+        match value.parse::<IpAddr>() {
+            Ok(ip) => Ok(IpRule(IpNet::from(ip))),
+            Err(_) => Err(format!("invalid address or CIDR range: {value}")),
+        }
+    }
+}
+
+impl IpRule {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.contains(&ip.to_canonical())
+    }
+}
+
+impl fmt::Display for IpRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.prefix_len() == self.0.max_prefix_len() {
+            true => self.0.addr().fmt(f),
+            false => self.0.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(try_from = "PortRuleRepr")]
+pub struct PortRule(RangeInclusive<u16>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRuleRepr {
+    Port(u16),
+    Range(String),
+}
+
+impl TryFrom<PortRuleRepr> for PortRule {
+    type Error = String;
+
+    fn try_from(value: PortRuleRepr) -> Result<Self, Self::Error> {
+        let range = match value {
+            PortRuleRepr::Port(port) => return Ok(PortRule(port..=port)),
+            PortRuleRepr::Range(range) => range,
+        };
+        let parse = |s: &str| s.trim().parse::<u16>().map_err(|_| format!("invalid port range: {range}"));
+        match range.split_once('-') {
+            Some((start, end)) => Ok(PortRule(parse(start)?..=parse(end)?)),
+            None => parse(&range).map(|port| PortRule(port..=port)),
+        }
+    }
+}
+
+impl PortRule {
+    pub fn matches(&self, port: u16) -> bool {
+        self.0.contains(&port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(toml: &str) -> Policy {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn ips(ips: &[&str]) -> Vec<IpAddr> {
+        ips.iter().map(|ip| ip.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn wildcard_match_edge_cases() {
+        let cases = [
+            ("example.com", "example.com", true),
+            ("example.com", "example.co", false),
+            ("example.com", "example.com.evil", false),
+            ("*", "", true),
+            ("*", "anything", true),
+            ("", "", true),
+            ("", "a", false),
+            ("*.example.com", "www.example.com", true),
+            ("*.example.com", "a.b.example.com", true),
+            ("*.example.com", "example.com", false),
+            ("*.example.com", "evilexample.com", false),
+            ("*.example.com", "www.example.com.evil", false),
+            ("www.*.com", "www.example.com", true),
+            ("www.*.com", "www.com", false),
+            ("a*a", "a", false),
+            ("a*a", "aa", true),
+            ("*ab*b", "ab", false),
+            ("*ab*b", "abb", true),
+            ("a**b", "ab", true),
+            ("/dns/*/tcp/443", "/dns/example.com/tcp/443", true),
+            ("/dns/*/tcp/443", "/dns/example.com/tcp/4430", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(wildcard_match(pattern, text), expected, "{pattern:?} against {text:?}");
+        }
+    }
+
+    #[test]
+    fn domain_patterns_are_normalized() {
+        let pattern = DomainPattern::from(String::from("*.Example.COM."));
+        assert!(pattern.matches(&normalize_domain("WWW.example.com.")));
+        assert!(!pattern.matches(&normalize_domain("example.com")));
+    }
+
+    #[test]
+    fn ip_rules_contain_addresses_and_ranges() {
+        let cases = [
+            ("192.0.2.1", "192.0.2.1", true),
+            ("192.0.2.1", "192.0.2.2", false),
+            ("192.0.2.0/24", "192.0.2.0", true),
+            ("192.0.2.0/24", "192.0.2.255", true),
+            ("192.0.2.0/24", "192.0.3.0", false),
+            ("192.0.2.0/24", "192.0.1.255", false),
+            ("192.0.2.0/24", "::ffff:192.0.2.7", true),
+            ("2001:db8::/32", "2001:db8:ffff::1", true),
+            ("2001:db8::/32", "2001:db9::", false),
+            ("0.0.0.0/0", "203.0.113.9", true),
+            ("0.0.0.0/0", "2001:db8::1", false),
+        ];
+        for (rule, ip, expected) in cases {
+            let rule = IpRule::try_from(rule.to_owned()).unwrap();
+            assert_eq!(rule.contains(ip.parse().unwrap()), expected, "{rule} containing {ip}");
+        }
+        assert!(IpRule::try_from(String::from("192.0.2.0/33")).is_err());
+        assert!(IpRule::try_from(String::from("example.com")).is_err());
+    }
+
+    #[test]
+    fn port_rules_match_ranges() {
+        let rule = |repr| PortRule::try_from(repr).unwrap();
+        assert!(rule(PortRuleRepr::Port(443)).matches(443));
+        assert!(!rule(PortRuleRepr::Port(443)).matches(444));
+        let range = rule(PortRuleRepr::Range(String::from("8000 - 8100")));
+        assert!(range.matches(8000) && range.matches(8100) && range.matches(8050));
+        assert!(!range.matches(7999) && !range.matches(8101));
+        assert!(rule(PortRuleRepr::Range(String::from("22"))).matches(22));
+        assert!(!rule(PortRuleRepr::Range(String::from("9000-8000"))).matches(8500));
+        for invalid in ["", "a-b", "1-", "70000", "1-2-3"] {
+            assert!(PortRule::try_from(PortRuleRepr::Range(invalid.to_owned())).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn check_destination_applies_ports_and_domains() {
+        let policy = policy(r#"
+            allowed_domains = ["*.example.com"]
+            denied_domains = ["secret.example.com"]
+            allowed_ports = [443, "8000-8100"]
+            denied_ports = [8080]
+        "#);
+        assert!(policy.check_destination(Some("www.Example.com."), 443).unwrap());
+        assert!(policy.check_destination(Some("www.example.com"), 8000).unwrap());
+        assert!(matches!(policy.check_destination(Some("www.example.com"), 8080), Err(PolicyViolation::DeniedPort(8080))));
+        assert!(matches!(policy.check_destination(Some("www.example.com"), 22), Err(PolicyViolation::PortNotAllowed(22))));
+        assert!(matches!(policy.check_destination(Some("SECRET.example.com"), 443), Err(PolicyViolation::DeniedDomain(_))));
+        assert!(matches!(policy.check_destination(Some("example.org"), 443), Err(PolicyViolation::DomainNotAllowed(_))));
+        // IP destinations are left to the address checks
+        assert!(!policy.check_destination(None, 443).unwrap());
+    }
+
+    #[test]
+    fn check_destination_defers_to_allowed_ips() {
+        let policy = policy(r#"
+            allowed_domains = ["*.example.com"]
+            allowed_ips = ["192.0.2.0/24"]
+        "#);
+        assert!(!policy.check_destination(Some("example.org"), 443).unwrap());
+        let mut resolved = ips(&["192.0.2.1", "93.184.216.34"]);
+        assert!(policy.filter_ips(&mut resolved, false).is_ok());
+        assert_eq!(resolved, ips(&["192.0.2.1"]));
+        let mut resolved = ips(&["93.184.216.34"]);
+        assert!(matches!(policy.filter_ips(&mut resolved, false), Err(PolicyViolation::NoAllowedIp(_))));
+        let mut resolved = ips(&["93.184.216.34"]);
+        assert!(policy.filter_ips(&mut resolved, true).is_ok());
+    }
+
+    #[test]
+    fn special_purpose_ranges_boundaries() {
+        let cases = [
+            // IPv4
+            ("0.0.0.0", Some("\"this network\"")),
+            ("0.255.255.255", Some("\"this network\"")),
+            ("1.0.0.0", None),
+            ("9.255.255.255", None),
+            ("10.0.0.0", Some("private")),
+            ("10.255.255.255", Some("private")),
+            ("11.0.0.0", None),
+            ("100.63.255.255", None),
+            ("100.64.0.0", Some("shared address space (CGNAT)")),
+            ("100.127.255.255", Some("shared address space (CGNAT)")),
+            ("100.128.0.0", None),
+            ("126.255.255.255", None),
+            ("127.0.0.0", Some("loopback")),
+            ("127.255.255.255", Some("loopback")),
+            ("128.0.0.0", None),
+            ("169.253.255.255", None),
+            ("169.254.0.0", Some("link-local")),
+            ("169.254.255.255", Some("link-local")),
+            ("169.255.0.0", None),
+            ("172.15.255.255", None),
+            ("172.16.0.0", Some("private")),
+            ("172.31.255.255", Some("private")),
+            ("172.32.0.0", None),
+            ("192.0.0.0", Some("IETF protocol assignments")),
+            ("192.0.1.0", None),
+            ("192.0.2.255", Some("documentation")),
+            ("192.88.99.1", Some("6to4 relay anycast")),
+            ("192.167.255.255", None),
+            ("192.168.0.0", Some("private")),
+            ("192.168.255.255", Some("private")),
+            ("192.169.0.0", None),
+            ("198.17.255.255", None),
+            ("198.18.0.0", Some("benchmarking")),
+            ("198.19.255.255", Some("benchmarking")),
+            ("198.20.0.0", None),
+            ("198.51.100.1", Some("documentation")),
+            ("203.0.113.255", Some("documentation")),
+            ("223.255.255.255", None),
+            ("224.0.0.0", Some("multicast")),
+            ("239.255.255.255", Some("multicast")),
+            ("240.0.0.0", Some("reserved")),
+            ("255.255.255.255", Some("reserved")),
+            ("8.8.8.8", None),
+            // IPv6
+            ("::", Some("unspecified")),
+            ("::1", Some("loopback")),
+            ("::2", Some("IPv4-compatible")),
+            ("::ffff:0.0.0.0", Some("\"this network\"")),
+            ("::ffff:127.0.0.1", Some("loopback")),
+            ("::ffff:10.0.0.1", Some("private")),
+            ("::ffff:169.254.1.1", Some("link-local")),
+            ("::ffff:100.64.0.1", Some("shared address space (CGNAT)")),
+            ("::ffff:8.8.8.8", None),
+            ("64:ff9b::7f00:1", Some("loopback")),
+            ("64:ff9b::a00:1", Some("private")),
+            ("64:ff9b::808:808", None),
+            ("64:ff9b:1::1", Some("local-use IPv4/IPv6 translation")),
+            ("64:ff9b:2::1", None),
+            ("100::", Some("discard-only")),
+            ("2001::1", Some("IETF protocol assignments")),
+            ("2001:1ff:ffff::", Some("IETF protocol assignments")),
+            ("2001:200::", None),
+            ("2001:db8::1", Some("documentation")),
+            ("2002:7f00:1::", Some("loopback")),
+            ("2002:808:808::", None),
+            ("fbff:ffff::", None),
+            ("fc00::", Some("unique local")),
+            ("fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", Some("unique local")),
+            ("fe00::", None),
+            ("fe7f:ffff::", None),
+            ("fe80::", Some("link-local")),
+            ("febf:ffff::", Some("link-local")),
+            ("fec0::", Some("site-local")),
+            ("ff00::", Some("multicast")),
+            ("ff02::1", Some("multicast")),
+            ("2606:4700::1111", None),
+        ];
+        for (ip, expected) in cases {
+            assert_eq!(special_purpose_range(ip.parse().unwrap()), expected, "{ip}");
+        }
+    }
+
+    #[test]
+    fn filter_ips_applies_denials_first() {
+        let policy = policy(r#"
+            allow_special_purpose_ips = true
+            allowed_ips = ["10.0.0.0/8"]
+            denied_ips = ["10.1.0.0/16"]
+        "#);
+        let mut resolved = ips(&["10.0.0.1", "10.1.0.1", "::ffff:10.1.2.3", "203.0.113.1"]);
+        assert!(policy.filter_ips(&mut resolved, false).is_ok());
+        assert_eq!(resolved, ips(&["10.0.0.1"]));
+        let mut resolved = ips(&["10.1.0.1"]);
+        assert!(matches!(policy.filter_ips(&mut resolved, true), Err(PolicyViolation::NoAllowedIp(_))));
+        assert!(policy.filter_ips(&mut Vec::new(), false).is_ok());
+    }
+}
@@ -0,0 +1,124 @@
+use std::{collections::BTreeMap, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use crate::*;
+
+/// A relay that is running, along with the way to terminate it.
+struct RelayEntry {
+    client_ip: Option<IpAddr>,
+    destination: Multiaddr,
+    remote_addr: Option<SocketAddr>,
+    multiplexed: bool,
+    started_at: u64,
+    meter: Arc<RelayMeter>,
+    kill: CancellationToken,
+}
+
+/// What the admin API shows of a relay.
+#[derive(Serialize)]
+pub struct RelaySummary {
+    pub id: u64,
+    pub client_ip: Option<IpAddr>,
+    pub destination: String,
+    /// The address the server connected to, which is the proxy's for destinations reached through egress proxies.
+    pub remote_addr: Option<SocketAddr>,
+    /// Whether the relay is a stream of a multiplexed websocket.
+    pub multiplexed: bool,
+    /// When the relay was opened, in seconds since the unix epoch.
+    pub started_at: u64,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+/// The relays the server is running, so that administrators can see and terminate them.
+#[derive(Default)]
+pub struct RelayRegistry {
+    next_id: AtomicU64,
+    relays: Mutex<BTreeMap<u64, RelayEntry>>,
+}
+
+/// Keeps a relay listed in the registry until dropped.
+pub struct RegisteredRelay {
+    registry: &'static RelayRegistry,
+    id: u64,
+    kill: CancellationToken,
+}
+
+impl RegisteredRelay {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Resolves once an administrator asked for the relay to be terminated.
+    pub async fn killed(&self) {
+        self.kill.cancelled().await
+    }
+}
+
+impl Drop for RegisteredRelay {
+    fn drop(&mut self) {
+        self.registry.relays.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl RelayRegistry {
+    pub fn register(&'static self, destination: &Multiaddr, remote_addr: Option<SocketAddr>, client_ip: Option<IpAddr>, multiplexed: bool, meter: &Arc<RelayMeter>) -> RegisteredRelay {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let kill = CancellationToken::new();
+        self.relays.lock().unwrap().insert(id, RelayEntry {
+            client_ip,
+            destination: destination.clone(),
+            remote_addr,
+            multiplexed,
+            started_at: now(),
+            meter: Arc::clone(meter),
+            kill: kill.clone(),
+        });
+        RegisteredRelay { registry: self, id, kill }
+    }
+
+    /// Lists the relays, oldest first.
+    pub fn list(&self) -> Vec<RelaySummary> {
+        self.relays.lock().unwrap().iter().map(|(id, relay)| RelaySummary {
+            id: *id,
+            client_ip: relay.client_ip,
+            destination: relay.destination.to_string(),
+            remote_addr: relay.remote_addr,
+            multiplexed: relay.multiplexed,
+            started_at: relay.started_at,
+            uploaded: relay.meter.uploaded.load(Ordering::Relaxed),
+            downloaded: relay.meter.downloaded.load(Ordering::Relaxed),
+        }).collect()
+    }
+
+    /// Terminates a relay, returning whether it was running.
+    pub fn kill(&self, id: u64) -> bool {
+        match self.relays.lock().unwrap().get(&id) {
+            Some(relay) => {
+                relay.kill.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Terminates the relays of the clients in a range, returning how many there were.
+    pub fn kill_client(&self, clients: &IpRule) -> usize {
+        self.kill_matching(|relay| relay.client_ip.is_some_and(|ip| clients.contains(ip)))
+    }
+
+    /// Terminates the relays to a banned destination, returning how many there were.
+    pub fn kill_destination(&self, ban: &DestinationBan) -> usize {
+        self.kill_matching(|relay| ban.matches(&relay.destination, relay.remote_addr.map(|addr| addr.ip())))
+    }
+
+    fn kill_matching(&self, matches: impl Fn(&RelayEntry) -> bool) -> usize {
+        let relays = self.relays.lock().unwrap();
+        let mut killed = 0;
+        for relay in relays.values().filter(|relay| matches(relay)) {
+            relay.kill.cancel();
+            killed += 1;
+        }
+        killed
+    }
+}
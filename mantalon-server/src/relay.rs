@@ -1,19 +1,289 @@
+use std::future::Future;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::sync::{mpsc, Semaphore};
 use crate::*;
 
-pub async fn relay_websocket_to_transport(mut receiver: WsReceiver, mut writer: Box<dyn AsyncWrite + Send + Unpin>) {
+/// The connection to the destination.
+pub enum Transport {
+    Stream(Box<dyn AsyncRead + Send + Unpin>, Box<dyn AsyncWrite + Send + Unpin>),
+    Datagram(UdpSocket),
+}
+
+/// Binds a UDP socket that only exchanges datagrams with `addr`.
+pub async fn connect_udp(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let local_addr: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local_addr).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// How long to wait between TCP keepalive probes once a connection went silent.
+const TCP_KEEPALIVE_PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sets the options of a connection to a destination.
+///
+/// Nagle's algorithm is disabled as relays already coalesce their writes, and keepalive probes are sent after `keepalive`
+/// of silence, if set, so that destinations that vanished are noticed.
+pub fn set_tcp_options(stream: &TcpStream, keepalive: Option<Duration>) -> std::io::Result<()> {
+    stream.set_nodelay(true)?;
+    if let Some(keepalive) = keepalive {
+        let params = TcpKeepalive::new().with_time(keepalive);
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "windows"))]
+        let params = params.with_interval(TCP_KEEPALIVE_PROBE_INTERVAL);
+        SockRef::from(stream).set_tcp_keepalive(&params)?;
+    }
+    Ok(())
+}
+
+/// How many buffers each direction of a TCP relay can have read but not written yet.
+const IN_FLIGHT_BUFFERS: usize = 4;
+
+/// The size of the buffer coalescing the websocket messages written to the destination.
+const TRANSPORT_WRITE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The buffers of one direction of a relay, which go from its reading half to its writing half and back.
+///
+/// The reading half waits for a free buffer before reading, which bounds the data in flight without allocating for each read.
+struct BufferPipe {
+    free: mpsc::Receiver<Vec<u8>>,
+    recycle: mpsc::Sender<Vec<u8>>,
+    filled: mpsc::Sender<(Vec<u8>, usize)>,
+    to_write: mpsc::Receiver<(Vec<u8>, usize)>,
+}
+
+impl BufferPipe {
+    fn new(buffer_size: usize) -> BufferPipe {
+        let (recycle, free) = mpsc::channel(IN_FLIGHT_BUFFERS);
+        for _ in 0..IN_FLIGHT_BUFFERS {
+            let _ = recycle.try_send(vec![0; buffer_size]);
+        }
+        let (filled, to_write) = mpsc::channel(IN_FLIGHT_BUFFERS);
+        BufferPipe { free, recycle, filled, to_write }
+    }
+}
+
+/// How a direction of a relay ended.
+#[derive(Debug, PartialEq)]
+pub enum DirectionEnd {
+    /// The source closed its side, and everything it sent was delivered.
+    Eof,
+    /// Reading or writing failed.
+    Broken,
+    /// The client sent a message larger than the maximum message size.
+    MessageTooLarge,
+}
+
+/// Runs the reading and writing halves of a relay direction, letting the writing half finish what was read once the reading half is done.
+///
+/// The writing half returns whether it wrote everything, as it only finishes before the reading half when writing fails.
+async fn run_pipe(read: impl Future<Output = Result<DirectionEnd, QuotaExceeded>>, write: impl Future<Output = bool>) -> Result<DirectionEnd, QuotaExceeded> {
+    let mut write = std::pin::pin!(write);
+    tokio::select! {
+        result = read => match write.await {
+            true => result,
+            false => Ok(DirectionEnd::Broken),
+        },
+        _ = &mut write => Ok(DirectionEnd::Broken),
+    }
+}
+
+/// Relays between a websocket and a TCP destination until both sides are closed, each direction draining on its own.
+///
+/// When the destination shuts down its side, what it sent is delivered and the websocket is closed with code 1000,
+/// after which the client can still send data until it answers with its own close frame. The destination's side is then shut down.
+/// When the client closes the websocket first, what it sent is written before the destination's side is shut down,
+/// but the relay ends right away as nothing can be sent over a websocket once its close frames were exchanged.
+pub async fn relay_stream(
+    receiver: WsReceiver,
+    sender: WsSender,
+    closer: &WsCloser,
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    buffer_size: usize,
+    meter: Arc<RelayMeter>,
+) -> Result<(), QuotaExceeded> {
+    let mut upload = std::pin::pin!(relay_websocket_to_transport(receiver, writer, buffer_size, Arc::clone(&meter)));
+    let download = relay_transport_to_websocket(reader, sender, buffer_size, meter);
+    tokio::select! {
+        result = &mut upload => {
+            let end = result?;
+            debug!("Websocket to transport finished: {end:?}");
+            if end == DirectionEnd::MessageTooLarge {
+                if let Err(e) = closer.send_close(CLOSE_MESSAGE_TOO_LARGE, "Message too large").await {
+                    debug!("Could not close websocket: {e}");
+                }
+            }
+            Ok(())
+        }
+        result = download => {
+            let end = result?;
+            debug!("Transport to websocket finished: {end:?}");
+            if end == DirectionEnd::Eof {
+                match closer.send_close(CLOSE_NORMAL, "").await {
+                    Ok(()) => debug!("Websocket to transport finished: {:?}", upload.await?),
+                    Err(e) => debug!("Could not close websocket: {e}"),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Writes the messages of the websocket to the destination, then shuts down the destination's side once the websocket is closed.
+///
+/// Messages received while the previous ones are being written are written together, and the destination is only flushed once there are none left.
+/// As messages can be larger than `buffer_size`, reading stops once they add up to the bytes of `IN_FLIGHT_BUFFERS` buffers, and buffers that grew
+/// larger are shrunk before being reused.
+async fn relay_websocket_to_transport(mut receiver: WsReceiver, writer: Box<dyn AsyncWrite + Send + Unpin>, buffer_size: usize, meter: Arc<RelayMeter>) -> Result<DirectionEnd, QuotaExceeded> {
+    let BufferPipe { mut free, recycle, filled, mut to_write } = BufferPipe::new(0);
+    let budget = (IN_FLIGHT_BUFFERS * buffer_size).min(u32::MAX as usize);
+    let in_flight = Semaphore::new(budget);
+    // Pongs wait behind the messages that are not read while the writing half catches up or bandwidth is shaped
+    let heartbeat = receiver.heartbeat();
+    let read = async {
+        let filled = filled;
+        loop {
+            let hold = heartbeat.hold();
+            let Some(mut message) = free.recv().await else { break };
+            drop(hold);
+            message.clear();
+            match receiver.receive_data(&mut message).await {
+                Ok(_) => (),
+                Err(SockettoError::Closed) => return Ok(DirectionEnd::Eof),
+                Err(SockettoError::MessageTooLarge { current, maximum }) => {
+                    debug!("Closing relay after a message of {current} bytes (maximum is {maximum})");
+                    return Ok(DirectionEnd::MessageTooLarge);
+                }
+                Err(e) => {
+                    error!("Websocket connection error: {e}");
+                    return Ok(DirectionEnd::Broken);
+                }
+            }
+            let n = message.len();
+            let _hold = heartbeat.hold();
+            meter.upload(n).await?;
+            in_flight.acquire_many(n.min(budget) as u32).await.expect("the semaphore is never closed").forget();
+            if filled.send((message, n)).await.is_err() {
+                break;
+            }
+        }
+        Ok(DirectionEnd::Broken)
+    };
+    let write = async {
+        let mut writer = tokio::io::BufWriter::with_capacity(TRANSPORT_WRITE_BUFFER_SIZE, writer);
+        while let Some(mut next) = to_write.recv().await {
+            loop {
+                let (mut message, n) = next;
+                if let Err(e) = writer.write_all(&message[..n]).await {
+                    debug!("Transport write error: {e}");
+                    return false;
+                }
+                in_flight.add_permits(n.min(budget));
+                message.clear();
+                message.shrink_to(buffer_size);
+                let _ = recycle.try_send(message);
+                match to_write.try_recv() {
+                    Ok(message) => next = message,
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = writer.flush().await {
+                debug!("Transport write error: {e}");
+                return false;
+            }
+        }
+
+        // Tell the destination that the client will not send anything more
+        if let Err(e) = writer.shutdown().await {
+            debug!("Could not shut down transport: {e}");
+        }
+        true
+    };
+    run_pipe(read, write).await
+}
+
+/// Sends what the destination sends as binary messages of up to `buffer_size` bytes, until the destination shuts down its side.
+///
+/// Messages read while the previous ones are being sent are sent together, and the websocket is only flushed once there are none left.
+async fn relay_transport_to_websocket(mut reader: Box<dyn AsyncRead + Send + Unpin>, mut sender: WsSender, buffer_size: usize, meter: Arc<RelayMeter>) -> Result<DirectionEnd, QuotaExceeded> {
+    let BufferPipe { mut free, recycle, filled, mut to_write } = BufferPipe::new(buffer_size);
+    let read = async {
+        let filled = filled;
+        while let Some(mut buffer) = free.recv().await {
+            let n = match reader.read(&mut buffer).await {
+                Ok(0) => return Ok(DirectionEnd::Eof),
+                Ok(n) => n,
+                Err(e) => {
+                    error!("Transport read error: {e}");
+                    return Ok(DirectionEnd::Broken);
+                }
+            };
+            meter.download(n).await?;
+            if filled.send((buffer, n)).await.is_err() {
+                break;
+            }
+        }
+        Ok(DirectionEnd::Broken)
+    };
+    let write = async {
+        loop {
+            let mut next = tokio::select! {
+                next = to_write.recv() => match next {
+                    Some(next) => next,
+                    None => break,
+                },
+                () = sender.ping_due() => {
+                    if let Err(e) = sender.send_ping().await {
+                        error!("Websocket connection error: {e}");
+                        return false;
+                    }
+                    continue;
+                }
+            };
+            loop {
+                let (buffer, n) = next;
+                if let Err(e) = async { sender.send_binary(&buffer[..n]).await?; sender.ping_if_due().await }.await {
+                    error!("Websocket connection error: {e}");
+                    return false;
+                }
+                let _ = recycle.try_send(buffer);
+                match to_write.try_recv() {
+                    Ok(buffer) => next = buffer,
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = sender.flush().await {
+                error!("Websocket connection error: {e}");
+                return false;
+            }
+        }
+        true
+    };
+    run_pipe(read, write).await
+}
+
+/// Sends each websocket message as one datagram.
+///
+/// Oversized messages are dropped by the receiver, which has its maximum message size set to the maximum datagram size.
+pub async fn relay_websocket_to_udp(mut receiver: WsReceiver, socket: Arc<UdpSocket>, meter: Arc<RelayMeter>) -> Result<(), QuotaExceeded> {
     let mut message = Vec::new();
     loop {
         message.clear();
         match receiver.receive_data(&mut message).await {
-            Ok(Data::Binary(n)) => {
-                assert_eq!(n, message.len());
-                writer.write_all(&message).await.unwrap();
-                writer.flush().await.unwrap();
+            Ok(_) => {
+                let _hold = receiver.heartbeat().hold();
+                meter.upload(message.len()).await?;
+                if let Err(e) = socket.send(&message).await {
+                    // Errors such as ICMP port unreachable are not fatal to a UDP flow
+                    debug!("Could not send datagram: {e}");
+                    continue;
+                }
             }
-            Ok(Data::Text(n)) => {
-                assert_eq!(n, message.len());
-                writer.write_all(&message).await.unwrap();
-                writer.flush().await.unwrap();
+            Err(SockettoError::MessageTooLarge { current, maximum }) => {
+                debug!("Dropping oversized datagram of {current} bytes (maximum is {maximum})");
             }
             Err(SockettoError::Closed) => break,
             Err(e) => {
@@ -22,26 +292,39 @@ pub async fn relay_websocket_to_transport(mut receiver: WsReceiver, mut writer:
             }
         }
     }
+    Ok(())
 }
 
-#[allow(clippy::uninit_vec)]
-pub async fn relay_transport_to_websocket(mut reader: Box<dyn AsyncRead + Send + Unpin>, mut sender: WsSender) {
-    let mut buffer = Vec::with_capacity(100_000);
-    unsafe {
-        buffer.set_len(buffer.capacity());
-    }
+/// Sends each received datagram as one websocket message.
+pub async fn relay_udp_to_websocket(socket: Arc<UdpSocket>, mut sender: WsSender, max_datagram_size: usize, meter: Arc<RelayMeter>) -> Result<(), QuotaExceeded> {
+    let mut buffer = vec![0; max_datagram_size];
     loop {
-        let n = match reader.read(&mut buffer).await {
+        let received = tokio::select! {
+            received = socket.recv(&mut buffer) => received,
+            () = sender.ping_due() => {
+                if let Err(e) = sender.send_ping().await {
+                    error!("Websocket connection error: {e}");
+                    break;
+                }
+                continue;
+            }
+        };
+        let n = match received {
             Ok(n) => n,
             Err(e) => {
-                error!("Transport read error: {e}");
-                break;
+                debug!("Could not receive datagram: {e}");
+                continue;
             }
         };
-        if n == 0 {
+        meter.download(n).await?;
+        if let Err(e) = sender.send_binary(&buffer[..n]).await {
+            error!("Websocket connection error: {e}");
+            break;
+        }
+        if let Err(e) = sender.flush().await {
+            error!("Websocket connection error: {e}");
             break;
         }
-        sender.send_binary(&buffer[..n]).await.unwrap();
-        sender.flush().await.unwrap();
     }
+    Ok(())
 }
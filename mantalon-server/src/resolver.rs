@@ -0,0 +1,440 @@
+use std::{fmt, fs::File, io::{BufReader as StdBufReader, Error as IoError, ErrorKind}, path::Path, str::FromStr};
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query, ResponseCode},
+    rr::{Name, RData, RecordType},
+};
+use http_body_util::BodyExt;
+use hyper::{client::conn::http2::SendRequest as Http2Sender, Uri};
+use hyper_util::rt::TokioExecutor;
+use tokio_rustls::{
+    rustls::{crypto::ring::default_provider, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use crate::*;
+
+/// An upstream DNS resolver, as in `udp://192.0.2.1:53`, `tcp://192.0.2.1`, `tls://dns.example` or `https://dns.example/dns-query`.
+#[derive(Debug, Clone)]
+pub enum DnsUpstream {
+    /// Plain DNS over UDP, retried over TCP when the answer is truncated.
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    /// DNS-over-TLS (RFC 7858). The host is used to verify the certificate.
+    Tls { host: String, port: u16 },
+    /// DNS-over-HTTPS (RFC 8484), over HTTP/2.
+    Https { host: String, port: u16, path: String },
+}
+
+impl FromStr for DnsUpstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let socket_addr = |addr: &str, default_port: u16| {
+            addr.parse::<SocketAddr>()
+                .or_else(|_| addr.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, default_port)))
+                .map_err(|_| format!("invalid address: {addr}"))
+        };
+        let uri = |s: &str, default_port: u16| {
+            let uri = s.parse::<Uri>().map_err(|e| format!("invalid upstream {s}: {e}"))?;
+            let host = uri.host().ok_or_else(|| format!("missing host in {s}"))?;
+            let host = host.trim_start_matches('[').trim_end_matches(']').to_owned();
+            Ok::<_, String>((host, uri.port_u16().unwrap_or(default_port), uri.path().to_owned()))
+        };
+
+        if let Some(addr) = s.strip_prefix("udp://") {
+            socket_addr(addr, 53).map(DnsUpstream::Udp)
+        } else if let Some(addr) = s.strip_prefix("tcp://") {
+            socket_addr(addr, 53).map(DnsUpstream::Tcp)
+        } else if s.starts_with("tls://") {
+            let (host, port, _) = uri(s, 853)?;
+            Ok(DnsUpstream::Tls { host, port })
+        } else if s.starts_with("https://") {
+            let (host, port, path) = uri(s, 443)?;
+            let path = if path.is_empty() || path == "/" { String::from("/dns-query") } else { path };
+            Ok(DnsUpstream::Https { host, port, path })
+        } else if s.contains("://") {
+            Err(format!("unsupported upstream {s}, use udp://, tcp://, tls:// or https://"))
+        } else {
+            socket_addr(s, 53).map(DnsUpstream::Udp)
+        }
+    }
+}
+
+fn format_host(host: &str) -> String {
+    match host.contains(':') {
+        true => format!("[{host}]"),
+        false => host.to_owned(),
+    }
+}
+
+impl fmt::Display for DnsUpstream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DnsUpstream::Udp(addr) => write!(f, "udp://{addr}"),
+            DnsUpstream::Tcp(addr) => write!(f, "tcp://{addr}"),
+            DnsUpstream::Tls { host, port } => write!(f, "tls://{}:{port}", format_host(host)),
+            DnsUpstream::Https { host, port, path } => write!(f, "https://{}:{port}{path}", format_host(host)),
+        }
+    }
+}
+
+/// Resolves domains with upstream resolvers, trying them in order, or with the system resolver if there are none.
+pub struct Resolver {
+    upstreams: Vec<DnsUpstream>,
+    /// How long each upstream has to answer before the next one is tried.
+    timeout: Duration,
+    dot_connector: TlsConnector,
+    doh_connector: TlsConnector,
+    /// HTTP/2 connections to the DoH upstreams, by upstream index, reused across lookups.
+    doh_connections: Vec<tokio::sync::Mutex<Option<Http2Sender<FullBody>>>>,
+}
+
+impl Resolver {
+    /// Creates a resolver. Certificates of DoT and DoH upstreams are verified against the web PKI and the certificates in `ca_file`.
+    pub fn new(upstreams: Vec<DnsUpstream>, timeout: Duration, ca_file: Option<&Path>) -> Result<Resolver, TlsError> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(path) = ca_file {
+            let file = File::open(path).map_err(|e| TlsError::Io(path.to_owned(), e))?;
+            let certs = rustls_pemfile::certs(&mut StdBufReader::new(file)).collect::<Result<Vec<_>, _>>().map_err(|e| TlsError::Io(path.to_owned(), e))?;
+            if certs.is_empty() {
+                return Err(TlsError::NoCertificate(path.to_owned()));
+            }
+            for cert in certs {
+                roots.add(cert).map_err(TlsError::Rustls)?;
+            }
+        }
+        let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(TlsError::Rustls)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut doh_config = config.clone();
+        doh_config.alpn_protocols = vec![b"h2".to_vec()];
+
+        Ok(Resolver {
+            doh_connections: upstreams.iter().map(|_| Default::default()).collect(),
+            upstreams,
+            timeout,
+            dot_connector: TlsConnector::from(Arc::new(config)),
+            doh_connector: TlsConnector::from(Arc::new(doh_config)),
+        })
+    }
+
+    /// Resolves the addresses of a domain, returning `None` on failures that should not be cached.
+    pub async fn lookup(&self, domain: &str) -> Option<Lookup> {
+        if self.upstreams.is_empty() {
+            return system_lookup(domain).await;
+        }
+        let responses = self.query_upstreams(&self.upstreams, domain, &[RecordType::AAAA, RecordType::A]).await?;
+
+        let mut ips = Vec::new();
+        let mut ttl: Option<u32> = None;
+        for answer in responses.iter().flat_map(|response| response.answers()) {
+            match answer.data() {
+                Some(RData::A(ip)) => ips.push(IpAddr::V4(ip.0)),
+                Some(RData::AAAA(ip)) => ips.push(IpAddr::V6(ip.0)),
+                _ => continue,
+            }
+            ttl = Some(ttl.map_or(answer.ttl(), |ttl| ttl.min(answer.ttl())));
+        }
+        Some(Lookup { ips, ttl: ttl.map(|ttl| Duration::from_secs(ttl.into())) })
+    }
+
+    /// Resolves the TXT records of a name, each joined into a single string.
+    ///
+    /// The system resolver cannot look up TXT records, so the nameservers of `/etc/resolv.conf` are queried over UDP when there are no upstreams.
+    pub async fn lookup_txt(&self, name: &str) -> Option<Vec<String>> {
+        let responses = match self.upstreams.is_empty() {
+            true => self.query_upstreams(&system_nameservers(), name, &[RecordType::TXT]).await?,
+            false => self.query_upstreams(&self.upstreams, name, &[RecordType::TXT]).await?,
+        };
+        let records = responses.iter().flat_map(|response| response.answers()).filter_map(|answer| match answer.data() {
+            Some(RData::TXT(txt)) => Some(txt.txt_data().iter().map(|data| String::from_utf8_lossy(data)).collect()),
+            _ => None,
+        });
+        Some(records.collect())
+    }
+
+    /// Sends queries for a name to upstreams in order until one answers all of them, returning `None` if none did.
+    async fn query_upstreams(&self, upstreams: &[DnsUpstream], domain: &str, record_types: &[RecordType]) -> Option<Vec<Message>> {
+        let Ok(name) = Name::from_ascii(domain) else {
+            error!("Invalid domain name: {domain}");
+            return Some(Vec::new());
+        };
+
+        for (index, upstream) in upstreams.iter().enumerate() {
+            let queries = futures::future::try_join_all(record_types.iter().map(|record_type| self.query(index, upstream, name.clone(), *record_type)));
+            match tokio::time::timeout(self.timeout, queries).await {
+                Ok(Ok(responses)) => return Some(responses),
+                Ok(Err(e)) => warn!("DNS upstream {upstream} failed to resolve {domain}: {e}"),
+                Err(_) => warn!("DNS upstream {upstream} timed out resolving {domain}"),
+            }
+        }
+        None
+    }
+
+    async fn query(&self, index: usize, upstream: &DnsUpstream, name: Name, record_type: RecordType) -> Result<Message, IoError> {
+        let id = rand::random();
+        let mut message = Message::new();
+        message
+            .set_id(id)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(name, record_type));
+        let request = message.to_vec().map_err(IoError::other)?;
+
+        let response = match upstream {
+            DnsUpstream::Udp(addr) => {
+                let response = query_udp(*addr, &request, id).await?;
+                match response.truncated() {
+                    true => query_stream(TcpStream::connect(addr).await?, &request).await?,
+                    false => response,
+                }
+            }
+            DnsUpstream::Tcp(addr) => query_stream(TcpStream::connect(addr).await?, &request).await?,
+            DnsUpstream::Tls { host, port } => {
+                let server_name = ServerName::try_from(host.clone()).map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?;
+                let stream = TcpStream::connect((host.as_str(), *port)).await?;
+                let stream = self.dot_connector.connect(server_name, stream).await?;
+                query_stream(stream, &request).await?
+            }
+            DnsUpstream::Https { host, port, path } => self.query_https(index, host, *port, path, request).await?,
+        };
+
+        if response.id() != id {
+            return Err(IoError::new(ErrorKind::InvalidData, "mismatched response id"));
+        }
+        match response.response_code() {
+            ResponseCode::NoError | ResponseCode::NXDomain => Ok(response),
+            code => Err(IoError::other(format!("upstream answered {code}"))),
+        }
+    }
+
+    async fn query_https(&self, index: usize, host: &str, port: u16, path: &str, request: Vec<u8>) -> Result<Message, IoError> {
+        let mut connection = self.doh_connections[index].lock().await;
+        let mut sender = match connection.as_ref().filter(|sender| !sender.is_closed()) {
+            Some(sender) => sender.clone(),
+            None => {
+                let server_name = ServerName::try_from(host.to_owned()).map_err(|e| IoError::new(ErrorKind::InvalidInput, e))?;
+                let stream = TcpStream::connect((host, port)).await?;
+                let stream = self.doh_connector.connect(server_name, stream).await?;
+                let (sender, conn) = hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await.map_err(IoError::other)?;
+                tokio::spawn(async move {
+                    if let Err(e) = conn.await {
+                        debug!("DNS-over-HTTPS connection closed: {e}");
+                    }
+                });
+                connection.insert(sender).clone()
+            }
+        };
+        drop(connection);
+
+        let request = Request::post(format!("https://{}:{port}{path}", format_host(host)))
+            .header("content-type", "application/dns-message")
+            .header("accept", "application/dns-message")
+            .body(FullBody::from(request))
+            .map_err(IoError::other)?;
+        let response = sender.send_request(request).await.map_err(IoError::other)?;
+        if !response.status().is_success() {
+            return Err(IoError::other(format!("upstream answered HTTP {}", response.status())));
+        }
+        let body = response.into_body().collect().await.map_err(IoError::other)?.to_bytes();
+        Message::from_vec(&body).map_err(|e| IoError::new(ErrorKind::InvalidData, e))
+    }
+}
+
+async fn query_udp(addr: SocketAddr, request: &[u8], id: u16) -> Result<Message, IoError> {
+    let socket = connect_udp(addr).await?;
+    socket.send(request).await?;
+    let mut buffer = vec![0; 4096];
+    loop {
+        let n = socket.recv(&mut buffer).await?;
+        // Ignore stray datagrams, including spoofing attempts
+        match Message::from_vec(&buffer[..n]) {
+            Ok(response) if response.id() == id => return Ok(response),
+            _ => continue,
+        }
+    }
+}
+
+/// Sends a query over a stream, prefixed by its length as in RFC 1035 section 4.2.2.
+async fn query_stream<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, request: &[u8]) -> Result<Message, IoError> {
+    let len = u16::try_from(request.len()).map_err(|_| IoError::new(ErrorKind::InvalidInput, "query too large"))?;
+    let mut framed = len.to_be_bytes().to_vec();
+    framed.extend_from_slice(request);
+    stream.write_all(&framed).await?;
+    stream.flush().await?;
+
+    let len = stream.read_u16().await?;
+    let mut response = vec![0; len.into()];
+    stream.read_exact(&mut response).await?;
+    Message::from_vec(&response).map_err(|e| IoError::new(ErrorKind::InvalidData, e))
+}
+
+/// Reads the nameservers the system resolver uses, which are expected to listen on UDP port 53.
+fn system_nameservers() -> Vec<DnsUpstream> {
+    let resolv_conf = std::fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| DnsUpstream::Udp(SocketAddr::new(ip, 53)))
+        .collect()
+}
+
+/// Resolves with the system resolver, which does not expose TTLs.
+///
+/// Only answers that the domain has no addresses are cached, as other failures can be temporary.
+async fn system_lookup(domain: &str) -> Option<Lookup> {
+    match tokio::net::lookup_host((domain, 0)).await {
+        Ok(addrs) => Some(Lookup { ips: addrs.map(|addr| addr.ip()).collect(), ttl: None }),
+        Err(e) if is_not_found(&e) => {
+            debug!("Domain {domain} has no addresses: {e}");
+            Some(Lookup { ips: Vec::new(), ttl: None })
+        }
+        Err(e) => {
+            debug!("Failed to resolve domain {domain}: {e}");
+            None
+        }
+    }
+}
+
+/// Whether the system resolver failed because the domain has no addresses, which std only tells in the error message.
+fn is_not_found(e: &IoError) -> bool {
+    // EAI_NONAME and EAI_NODATA, as worded by glibc, musl and the BSDs
+    const MESSAGES: [&str; 5] = [
+        "Name or service not known",
+        "No address associated with hostname",
+        "Name does not resolve",
+        "Name has no usable address",
+        "nodename nor servname provided, or not known",
+    ];
+    let message = e.to_string();
+    MESSAGES.iter().any(|not_found| message.contains(not_found))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use hickory_proto::rr::{rdata::A, Record};
+    use tokio::net::{TcpListener, UdpSocket};
+    use super::*;
+
+    #[derive(Clone, Copy)]
+    enum StubMode {
+        Answer,
+        /// Answers over UDP are truncated and empty, so the full answer is only available over TCP.
+        TruncateUdp,
+        NxDomain,
+    }
+
+    /// A DNS server on 127.0.0.1 that answers A queries with 192.0.2.1 and counts the queries it gets.
+    struct Stub {
+        addr: SocketAddr,
+        udp_queries: Arc<AtomicUsize>,
+        tcp_queries: Arc<AtomicUsize>,
+    }
+
+    fn stub_response(request: &[u8], mode: StubMode, over_udp: bool) -> Vec<u8> {
+        let request = Message::from_vec(request).unwrap();
+        let mut response = Message::new();
+        response.set_id(request.id()).set_message_type(MessageType::Response).add_queries(request.queries().to_vec());
+        let query = &request.queries()[0];
+        match mode {
+            StubMode::NxDomain => {
+                response.set_response_code(ResponseCode::NXDomain);
+            }
+            StubMode::TruncateUdp if over_udp => {
+                response.set_truncated(true);
+            }
+            StubMode::Answer | StubMode::TruncateUdp => {
+                if query.query_type() == RecordType::A {
+                    response.add_answer(Record::from_rdata(query.name().clone(), 300, RData::A(A::new(192, 0, 2, 1))));
+                }
+            }
+        }
+        response.to_vec().unwrap()
+    }
+
+    async fn stub(mode: StubMode) -> Stub {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        let udp_queries = Arc::new(AtomicUsize::new(0));
+        let tcp_queries = Arc::new(AtomicUsize::new(0));
+
+        let queries = Arc::clone(&udp_queries);
+        tokio::spawn(async move {
+            let mut buffer = vec![0; 4096];
+            while let Ok((n, peer)) = socket.recv_from(&mut buffer).await {
+                queries.fetch_add(1, Ordering::SeqCst);
+                socket.send_to(&stub_response(&buffer[..n], mode, true), peer).await.unwrap();
+            }
+        });
+        let queries = Arc::clone(&tcp_queries);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                queries.fetch_add(1, Ordering::SeqCst);
+                let len = stream.read_u16().await.unwrap();
+                let mut request = vec![0; len.into()];
+                stream.read_exact(&mut request).await.unwrap();
+                let response = stub_response(&request, mode, false);
+                stream.write_u16(response.len() as u16).await.unwrap();
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        Stub { addr, udp_queries, tcp_queries }
+    }
+
+    fn resolver(upstreams: Vec<DnsUpstream>) -> Resolver {
+        Resolver::new(upstreams, Duration::from_secs(5), None).unwrap()
+    }
+
+    fn assert_answered(lookup: Option<Lookup>) {
+        let lookup = lookup.expect("the stub answered");
+        assert_eq!(lookup.ips, vec![IpAddr::from([192, 0, 2, 1])]);
+        assert_eq!(lookup.ttl, Some(Duration::from_secs(300)));
+    }
+
+    #[tokio::test]
+    async fn udp_upstream() {
+        let stub = stub(StubMode::Answer).await;
+        assert_answered(resolver(vec![DnsUpstream::Udp(stub.addr)]).lookup("example.com").await);
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 2);
+        assert_eq!(stub.tcp_queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn tcp_upstream() {
+        let stub = stub(StubMode::Answer).await;
+        assert_answered(resolver(vec![DnsUpstream::Tcp(stub.addr)]).lookup("example.com").await);
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 0);
+        assert_eq!(stub.tcp_queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn truncated_udp_answers_are_retried_over_tcp() {
+        let stub = stub(StubMode::TruncateUdp).await;
+        assert_answered(resolver(vec![DnsUpstream::Udp(stub.addr)]).lookup("example.com").await);
+        assert_eq!(stub.udp_queries.load(Ordering::SeqCst), 2);
+        assert_eq!(stub.tcp_queries.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn nxdomain_is_not_retried_with_the_next_upstream() {
+        let nxdomain = stub(StubMode::NxDomain).await;
+        let answer = stub(StubMode::Answer).await;
+        let lookup = resolver(vec![DnsUpstream::Udp(nxdomain.addr), DnsUpstream::Udp(answer.addr)]).lookup("example.com").await;
+        assert!(lookup.expect("NXDOMAIN is an answer").ips.is_empty());
+        assert_eq!(answer.udp_queries.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn failing_upstreams_are_skipped() {
+        // A port that was just freed, so connecting to it is refused
+        let closed = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let answer = stub(StubMode::Answer).await;
+        assert_answered(resolver(vec![DnsUpstream::Tcp(closed), DnsUpstream::Udp(answer.addr)]).lookup("example.com").await);
+    }
+}
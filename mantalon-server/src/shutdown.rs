@@ -0,0 +1,74 @@
+use std::future::Future;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use crate::*;
+
+/// Coordinates the graceful shutdown of the server.
+///
+/// Shutting down happens in two steps: listeners stop accepting connections and new relays are refused,
+/// then once the deadline is reached, relays still open are closed with the "going away" code.
+#[derive(Default)]
+pub struct Shutdown {
+    stopping: CancellationToken,
+    going_away: CancellationToken,
+    relays: TaskTracker,
+}
+
+impl Shutdown {
+    /// Whether the server stopped accepting new relays.
+    pub fn is_stopping(&self) -> bool {
+        self.stopping.is_cancelled()
+    }
+
+    /// Resolves once the server stops accepting new connections.
+    pub async fn stopping(&self) {
+        self.stopping.cancelled().await
+    }
+
+    /// Resolves once relays should be closed.
+    pub async fn going_away(&self) {
+        self.going_away.cancelled().await
+    }
+
+    /// Spawns a relay task that shutting down waits for.
+    pub fn spawn_relay<F: Future<Output = ()> + Send + 'static>(&self, relay: F) {
+        self.relays.spawn(relay);
+    }
+
+    /// Stops accepting, lets relays finish for up to `deadline`, then closes the remaining ones.
+    pub async fn drain(&self, deadline: Duration) {
+        self.stopping.cancel();
+        self.relays.close();
+        if !self.relays.is_empty() {
+            info!("Waiting up to {deadline:?} for {} relays to finish", self.relays.len());
+        }
+        if tokio::time::timeout(deadline, self.relays.wait()).await.is_err() {
+            info!("Closing the {} remaining relays", self.relays.len());
+            self.going_away.cancel();
+            // Give the close frames a chance to be sent
+            let _ = tokio::time::timeout(Duration::from_secs(5), self.relays.wait()).await;
+        }
+    }
+}
+
+/// Resolves when the process is asked to terminate, with SIGTERM or SIGINT.
+pub async fn termination_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        tokio::select! {
+            _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+            _ = interrupt.recv() => info!("Received SIGINT, shutting down"),
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await?;
+        info!("Received Ctrl-C, shutting down");
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+use std::io::Error as IoError;
+use hyper::header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION, VARY};
+use hyper_staticfile::{util::FileResponseBuilder, AcceptEncoding, Body as StaticBody, ResolveResult, Resolver};
+use crate::*;
+
+/// Serves the portal and the client from a directory.
+///
+/// Paths that match no file are answered with `index.html`, as the portal handles them, except under `/mantalon/`.
+pub struct StaticFiles {
+    resolver: Resolver,
+    service_worker_allowed: Option<HeaderValue>,
+}
+
+/// Whether an `If-None-Match` header lists an ETag, using the weak comparison of RFC 9110 section 8.8.3.2.
+fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let (Ok(if_none_match), Ok(etag)) = (if_none_match.to_str(), etag.to_str()) else {
+        return false;
+    };
+    let etag = etag.trim_start_matches("W/");
+    if_none_match.split(',').map(str::trim).any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+impl StaticFiles {
+    pub fn new(root: PathBuf, service_worker_allowed: Option<HeaderValue>) -> StaticFiles {
+        let mut resolver = Resolver::new(root);
+        resolver.allowed_encodings = AcceptEncoding::all();
+        StaticFiles { resolver, service_worker_allowed }
+    }
+
+    pub async fn serve<B>(&self, req: &Request<B>) -> Result<Response<StaticBody>, IoError> {
+        let mut result = self.resolver.resolve_request(req).await?;
+        if matches!(result, ResolveResult::NotFound) && !req.uri().path().starts_with("/mantalon/") {
+            result = self.resolver.resolve_path("/index.html", self.resolver.allowed_encodings & accept_encoding(req)).await?;
+        }
+
+        let file = match result {
+            ResolveResult::Found(file) => file,
+            ResolveResult::MethodNotMatched => return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED)),
+            ResolveResult::NotFound => return Ok(status_response(StatusCode::NOT_FOUND)),
+            ResolveResult::PermissionDenied => return Ok(status_response(StatusCode::FORBIDDEN)),
+            ResolveResult::IsDirectory { redirect_to } => {
+                let mut response = status_response(StatusCode::MOVED_PERMANENTLY);
+                response.headers_mut().insert(LOCATION, HeaderValue::try_from(redirect_to).map_err(IoError::other)?);
+                return Ok(response);
+            }
+        };
+        let content_type = file.content_type.clone();
+        let mut response = FileResponseBuilder::new().request(req).build(file).map_err(IoError::other)?;
+        // Responses to HEAD requests are built without it
+        if let (false, Some(content_type)) = (response.headers().contains_key(CONTENT_TYPE), content_type) {
+            response.headers_mut().insert(CONTENT_TYPE, HeaderValue::try_from(content_type).map_err(IoError::other)?);
+        }
+
+        // Let browsers cache files as long as they revalidate them
+        if let (Some(if_none_match), Some(etag)) = (req.headers().get(IF_NONE_MATCH), response.headers().get(ETAG)) {
+            if etag_matches(if_none_match, etag) {
+                let etag = etag.clone();
+                response = status_response(StatusCode::NOT_MODIFIED);
+                response.headers_mut().insert(ETAG, etag);
+            }
+        }
+        let headers = response.headers_mut();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+        headers.insert(VARY, HeaderValue::from_static("accept-encoding"));
+        let is_javascript = headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).is_some_and(|value| value.contains("javascript"));
+        if let (true, Some(service_worker_allowed)) = (is_javascript, &self.service_worker_allowed) {
+            headers.insert("service-worker-allowed", service_worker_allowed.clone());
+        }
+        Ok(response)
+    }
+}
+
+fn accept_encoding<B>(req: &Request<B>) -> AcceptEncoding {
+    req.headers().get(hyper::header::ACCEPT_ENCODING).map(AcceptEncoding::from_header_value).unwrap_or(AcceptEncoding::none())
+}
+
+fn status_response(status: StatusCode) -> Response<StaticBody> {
+    let mut response = Response::new(StaticBody::Empty);
+    *response.status_mut() = status;
+    response
+}
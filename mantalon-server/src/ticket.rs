@@ -0,0 +1,233 @@
+use std::{fmt, path::Path};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::*;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The shortest key accepted, as shorter keys make tickets easy to forge.
+pub const MIN_TICKET_KEY_SIZE: usize = 16;
+
+/// The prefix of `Sec-WebSocket-Protocol` values carrying a ticket.
+pub const TICKET_PROTOCOL_PREFIX: &str = "mantalon-ticket.";
+
+/// The secret key used to sign and verify connect tickets.
+///
+/// A ticket is `base64url(payload) "." base64url(HMAC-SHA256(key, payload))`, where payload is
+/// `expiry "\n" client_ip "\n" destination_pattern`, `expiry` being a unix timestamp in seconds
+/// and `client_ip` being empty when the ticket can be used from anywhere.
+pub struct TicketKey(Vec<u8>);
+
+#[derive(Debug)]
+pub enum TicketError {
+    Missing,
+    Malformed,
+    BadSignature,
+    Expired,
+    WrongClient,
+    WrongDestination,
+}
+
+impl fmt::Display for TicketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TicketError::Missing => write!(f, "a ticket is required"),
+            TicketError::Malformed => write!(f, "the ticket is malformed"),
+            TicketError::BadSignature => write!(f, "the ticket signature is invalid"),
+            TicketError::Expired => write!(f, "the ticket has expired"),
+            TicketError::WrongClient => write!(f, "the ticket was issued to another client"),
+            TicketError::WrongDestination => write!(f, "the ticket does not cover this destination"),
+        }
+    }
+}
+
+impl TicketKey {
+    /// Reads the key from a file. Surrounding whitespace is ignored, and keys shorter than [`MIN_TICKET_KEY_SIZE`] bytes are refused.
+    pub fn load(path: &Path) -> std::io::Result<TicketKey> {
+        let content = std::fs::read(path)?;
+        let key = content.trim_ascii();
+        if key.len() < MIN_TICKET_KEY_SIZE {
+            let message = format!("{} holds {} bytes, but keys must have at least {MIN_TICKET_KEY_SIZE}", path.display(), key.len());
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+        }
+        Ok(TicketKey(key.to_vec()))
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.0).expect("HMAC accepts keys of any size")
+    }
+
+    /// Issues a ticket for the destinations matching `pattern`, in which `*` stands for any sequence of characters.
+    pub fn sign(&self, pattern: &str, expiry: u64, client_ip: Option<IpAddr>) -> String {
+        let client_ip = client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+        let payload = format!("{expiry}\n{client_ip}\n{pattern}");
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        let signature = mac.finalize().into_bytes();
+        format!("{}.{}", URL_SAFE_NO_PAD.encode(payload), URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    /// Checks that `ticket` allows `client_ip` to connect to `destination`.
+    pub fn verify(&self, ticket: &str, destination: &str, client_ip: Option<IpAddr>) -> Result<(), TicketError> {
+        let (payload, signature) = ticket.split_once('.').ok_or(TicketError::Malformed)?;
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| TicketError::Malformed)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| TicketError::Malformed)?;
+
+        let mut mac = self.mac();
+        mac.update(&payload);
+        mac.verify_slice(&signature).map_err(|_| TicketError::BadSignature)?;
+
+        let payload = String::from_utf8(payload).map_err(|_| TicketError::Malformed)?;
+        let mut fields = payload.splitn(3, '\n');
+        let (Some(expiry), Some(ticket_ip), Some(pattern)) = (fields.next(), fields.next(), fields.next()) else {
+            return Err(TicketError::Malformed);
+        };
+
+        let expiry: u64 = expiry.parse().map_err(|_| TicketError::Malformed)?;
+        if now() > expiry {
+            return Err(TicketError::Expired);
+        }
+        if !ticket_ip.is_empty() {
+            let ticket_ip: IpAddr = ticket_ip.parse().map_err(|_| TicketError::Malformed)?;
+            if client_ip.map(|ip| ip.to_canonical()) != Some(ticket_ip.to_canonical()) {
+                return Err(TicketError::WrongClient);
+            }
+        }
+        if !wildcard_match(pattern, destination) {
+            return Err(TicketError::WrongDestination);
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds the ticket in the `ticket` query parameter or in a `Sec-WebSocket-Protocol` value.
+///
+/// Returns the ticket along with the protocol value it was found in, which must be echoed back to the client.
+pub fn extract_ticket<B>(req: &Request<B>) -> Option<(String, Option<String>)> {
+    let query_ticket = req.uri().query().and_then(|query| {
+        query.split('&').find_map(|pair| pair.strip_prefix("ticket=")).map(String::from)
+    });
+    if let Some(ticket) = query_ticket {
+        return Some((ticket, None));
+    }
+
+    req.headers()
+        .get_all("sec-websocket-protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .find_map(|protocol| {
+            let ticket = protocol.strip_prefix(TICKET_PROTOCOL_PREFIX)?;
+            Some((ticket.to_owned(), Some(protocol.to_owned())))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESTINATION: &str = "/dns/www.example.com/tcp/443";
+
+    fn key() -> TicketKey {
+        TicketKey(b"0123456789abcdef0123456789abcdef".to_vec())
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn signed_tickets_verify() {
+        let ticket = key().sign("/dns/*.example.com/tcp/443", now() + 60, ip("192.0.2.1"));
+        assert!(key().verify(&ticket, DESTINATION, ip("192.0.2.1")).is_ok());
+    }
+
+    #[test]
+    fn expired_tickets_are_refused() {
+        let ticket = key().sign("*", now() - 1, None);
+        assert!(matches!(key().verify(&ticket, DESTINATION, None), Err(TicketError::Expired)));
+    }
+
+    #[test]
+    fn tickets_are_bound_to_their_client() {
+        let ticket = key().sign("*", now() + 60, ip("192.0.2.1"));
+        assert!(matches!(key().verify(&ticket, DESTINATION, ip("192.0.2.2")), Err(TicketError::WrongClient)));
+        assert!(matches!(key().verify(&ticket, DESTINATION, None), Err(TicketError::WrongClient)));
+        assert!(key().verify(&ticket, DESTINATION, ip("::ffff:192.0.2.1")).is_ok());
+        assert!(matches!(key().verify(&ticket, DESTINATION, ip("::ffff:192.0.2.2")), Err(TicketError::WrongClient)));
+    }
+
+    #[test]
+    fn tickets_without_client_work_from_anywhere() {
+        let ticket = key().sign("*", now() + 60, None);
+        assert!(key().verify(&ticket, DESTINATION, ip("192.0.2.1")).is_ok());
+        assert!(key().verify(&ticket, DESTINATION, ip("2001:db8::1")).is_ok());
+        assert!(key().verify(&ticket, DESTINATION, None).is_ok());
+    }
+
+    #[test]
+    fn tickets_only_cover_their_pattern() {
+        let ticket = key().sign("/dns/*.example.com/tcp/443", now() + 60, None);
+        assert!(matches!(key().verify(&ticket, "/dns/www.example.org/tcp/443", None), Err(TicketError::WrongDestination)));
+        assert!(matches!(key().verify(&ticket, "/dns/www.example.com/tcp/22", None), Err(TicketError::WrongDestination)));
+    }
+
+    #[test]
+    fn tampered_tickets_are_refused() {
+        let ticket = key().sign("/dns/*.example.com/tcp/443", now() + 60, None);
+        let (payload, signature) = ticket.split_once('.').unwrap();
+
+        let forged_payload = URL_SAFE_NO_PAD.encode(format!("{}\n\n*", now() + 60));
+        let forged = format!("{forged_payload}.{signature}");
+        assert!(matches!(key().verify(&forged, DESTINATION, None), Err(TicketError::BadSignature)));
+
+        let mut signature = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        signature[0] ^= 1;
+        let forged = format!("{payload}.{}", URL_SAFE_NO_PAD.encode(signature));
+        assert!(matches!(key().verify(&forged, DESTINATION, None), Err(TicketError::BadSignature)));
+
+        let other_key = TicketKey(b"fedcba9876543210fedcba9876543210".to_vec());
+        assert!(matches!(other_key.verify(&ticket, DESTINATION, None), Err(TicketError::BadSignature)));
+    }
+
+    #[test]
+    fn malformed_tickets_are_refused() {
+        let ticket = key().sign("*", now() + 60, None);
+        let (payload, signature) = ticket.split_once('.').unwrap();
+        for malformed in [payload, &format!("{payload}{signature}"), &format!("{payload}!.{signature}"), &format!("{payload}.{signature}!"), ""] {
+            assert!(matches!(key().verify(malformed, DESTINATION, None), Err(TicketError::Malformed)), "{malformed:?}");
+        }
+    }
+
+    #[test]
+    fn short_keys_are_refused() {
+        let path = std::env::temp_dir().join(format!("mantalon-ticket-key-{}", std::process::id()));
+        std::fs::write(&path, format!("  {}\n", "k".repeat(MIN_TICKET_KEY_SIZE - 1))).unwrap();
+        let short = TicketKey::load(&path);
+        std::fs::write(&path, format!("  {}\n", "k".repeat(MIN_TICKET_KEY_SIZE))).unwrap();
+        let long_enough = TicketKey::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(short.err().map(|e| e.kind()), Some(std::io::ErrorKind::InvalidData));
+        assert_eq!(long_enough.unwrap().0.len(), MIN_TICKET_KEY_SIZE);
+    }
+
+    #[test]
+    fn tickets_are_extracted_from_query_and_protocols() {
+        let req = Request::builder().uri("/?other=1&ticket=abc.def").body(()).unwrap();
+        assert_eq!(extract_ticket(&req), Some((String::from("abc.def"), None)));
+
+        let req = Request::builder()
+            .uri("/")
+            .header("Sec-WebSocket-Protocol", "mantalon, mantalon-ticket.abc.def")
+            .body(())
+            .unwrap();
+        assert_eq!(extract_ticket(&req), Some((String::from("abc.def"), Some(String::from("mantalon-ticket.abc.def")))));
+
+        let req = Request::builder().uri("/?tickets=1").header("Sec-WebSocket-Protocol", "mantalon").body(()).unwrap();
+        assert_eq!(extract_ticket(&req), None);
+    }
+}
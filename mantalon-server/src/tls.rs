@@ -0,0 +1,103 @@
+use std::{fmt, fs::File, io::BufReader as StdBufReader, path::{Path, PathBuf}, sync::RwLock};
+use tokio_rustls::{
+    rustls::{crypto::ring::{default_provider, sign::any_supported_type}, server::{ClientHello, ResolvesServerCert}, sign::CertifiedKey, ServerConfig},
+    TlsAcceptor,
+};
+use crate::*;
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(PathBuf, std::io::Error),
+    NoCertificate(PathBuf),
+    NoPrivateKey(PathBuf),
+    Rustls(tokio_rustls::rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io(path, e) => write!(f, "Could not read {}: {e}", path.display()),
+            TlsError::NoCertificate(path) => write!(f, "No certificate found in {}", path.display()),
+            TlsError::NoPrivateKey(path) => write!(f, "No private key found in {}", path.display()),
+            TlsError::Rustls(e) => write!(f, "Invalid TLS configuration: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+/// A certificate loaded from PEM files, that can be reloaded without affecting established connections.
+#[derive(Debug)]
+pub struct ReloadableCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadableCert {
+    pub fn load(cert_path: PathBuf, key_path: PathBuf) -> Result<ReloadableCert, TlsError> {
+        let current = load_certified_key(&cert_path, &key_path)?;
+        Ok(ReloadableCert { cert_path, key_path, current: RwLock::new(Arc::new(current)) })
+    }
+
+    /// Reads the PEM files again. The previous certificate is kept if they are invalid.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let new = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(new);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().unwrap()))
+    }
+}
+
+fn open(path: &Path) -> Result<StdBufReader<File>, TlsError> {
+    File::open(path).map(StdBufReader::new).map_err(|e| TlsError::Io(path.to_owned(), e))
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let certs = rustls_pemfile::certs(&mut open(cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Io(cert_path.to_owned(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(cert_path.to_owned()));
+    }
+
+    let key = rustls_pemfile::private_key(&mut open(key_path)?)
+        .map_err(|e| TlsError::Io(key_path.to_owned(), e))?
+        .ok_or_else(|| TlsError::NoPrivateKey(key_path.to_owned()))?;
+    let key = any_supported_type(&key).map_err(TlsError::Rustls)?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Builds a TLS acceptor serving the current certificate of `cert`.
+pub fn tls_acceptor(cert: Arc<ReloadableCert>) -> Result<TlsAcceptor, TlsError> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(TlsError::Rustls)?
+        .with_no_client_auth()
+        .with_cert_resolver(cert);
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Reloads the certificate each time the process receives SIGHUP.
+#[cfg(unix)]
+pub fn reload_cert_on_sighup(cert: Arc<ReloadableCert>) -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match cert.reload() {
+                Ok(()) => info!("Reloaded TLS certificate"),
+                Err(e) => error!("Could not reload TLS certificate, keeping the previous one: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,379 @@
+use std::{io::{Error as IoError, Result as IoResult}, pin::Pin, sync::{atomic::{AtomicUsize, Ordering}, Mutex}, task::{Context, Poll}};
+use futures::{io::{AsyncRead as FuturesRead, AsyncWrite as FuturesWrite}, AsyncWriteExt as _, FutureExt as _};
+use soketto::{data::ByteSlice125, Incoming as WsIncoming};
+use crate::*;
+
+/// The relay or stream was closed normally.
+pub const CLOSE_NORMAL: u16 = 1000;
+
+/// The server is shutting down, so the client should reconnect elsewhere.
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// The client sent a message larger than the maximum message size.
+pub const CLOSE_MESSAGE_TOO_LARGE: u16 = 1009;
+
+pub type WsStream = BufReader<SharedIo<BufWriter<HeartbeatIo<Compat<TokioIo<Upgraded>>>>>>;
+
+/// How often to ping clients, and how long they have to answer.
+#[derive(Debug, Clone, Copy)]
+pub struct Keepalive {
+    /// Zero disables pings.
+    pub ping_interval: Duration,
+    pub pong_timeout: Duration,
+}
+
+/// Tracks when the client was last heard from, to notice clients that vanished without closing the websocket.
+pub struct Heartbeat {
+    keepalive: Keepalive,
+    last_seen: Mutex<Instant>,
+    /// How many [`HeartbeatHold`]s are alive.
+    holds: AtomicUsize,
+}
+
+impl Heartbeat {
+    fn seen(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+
+    /// Resolves once the client has not answered a ping in time, nor sent anything since. Never resolves when pings are disabled.
+    pub async fn dead(&self) {
+        let Keepalive { ping_interval, pong_timeout } = self.keepalive;
+        if ping_interval.is_zero() {
+            return std::future::pending().await;
+        }
+        loop {
+            let deadline = *self.last_seen.lock().unwrap() + ping_interval + pong_timeout;
+            if Instant::now() >= deadline {
+                if self.holds.load(Ordering::Relaxed) == 0 {
+                    return;
+                }
+                self.seen();
+                continue;
+            }
+            tokio::time::sleep_until(deadline.into()).await;
+        }
+    }
+
+    /// Keeps the client from being considered dead while the relay stops reading from it, as its answers to pings then
+    /// wait behind the data it already sent. The client gets a full ping interval and pong timeout once the hold is dropped.
+    pub fn hold(self: &Arc<Self>) -> HeartbeatHold {
+        self.holds.fetch_add(1, Ordering::Relaxed);
+        HeartbeatHold(Arc::clone(self))
+    }
+}
+
+pub struct HeartbeatHold(Arc<Heartbeat>);
+
+impl Drop for HeartbeatHold {
+    fn drop(&mut self) {
+        self.0.seen();
+        self.0.holds.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The connection to the client, which tells the heartbeat the client is alive whenever it sends bytes, or makes room
+/// for more after the connection was full.
+///
+/// This keeps busy clients alive while their pongs are stuck behind large messages, in either direction.
+pub struct HeartbeatIo<T> {
+    io: T,
+    heartbeat: Arc<Heartbeat>,
+    /// Writing was blocked as the client did not acknowledge what was already sent.
+    blocked: bool,
+}
+
+impl<T: FuturesRead + Unpin> FuturesRead for HeartbeatIo<T> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        let result = Pin::new(&mut self.io).poll_read(cx, buf);
+        if let Poll::Ready(Ok(1..)) = result {
+            self.heartbeat.seen();
+        }
+        result
+    }
+}
+
+impl<T: FuturesWrite + Unpin> FuturesWrite for HeartbeatIo<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let result = Pin::new(&mut self.io).poll_write(cx, buf);
+        match result {
+            Poll::Pending => self.blocked = true,
+            Poll::Ready(Ok(1..)) if self.blocked => {
+                self.blocked = false;
+                self.heartbeat.seen();
+            }
+            _ => (),
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.io).poll_close(cx)
+    }
+}
+
+/// The sending half of a websocket, which also pings the client.
+pub struct WsSender {
+    sender: Sender<WsStream>,
+    pings: Option<tokio::time::Interval>,
+}
+
+impl WsSender {
+    pub async fn send_binary(&mut self, data: &[u8]) -> Result<(), SockettoError> {
+        self.sender.send_binary(data).await
+    }
+
+    pub async fn flush(&mut self) -> Result<(), SockettoError> {
+        self.sender.flush().await
+    }
+
+    /// Waits until the next ping is due, forever when pings are disabled.
+    ///
+    /// Can be cancelled, unlike sending, so that it can be raced with the messages to send.
+    pub async fn ping_due(&mut self) {
+        match &mut self.pings {
+            Some(pings) => {
+                pings.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    pub async fn send_ping(&mut self) -> Result<(), SockettoError> {
+        self.sender.send_ping(ByteSlice125::try_from(&[][..]).expect("empty payloads fit in control frames")).await?;
+        self.sender.flush().await
+    }
+
+    /// Sends a ping if one is due, so that pings keep going out between the messages of a busy relay.
+    pub async fn ping_if_due(&mut self) -> Result<(), SockettoError> {
+        if self.ping_due().now_or_never().is_some() {
+            self.send_ping().await?;
+        }
+        Ok(())
+    }
+}
+
+/// The receiving half of a websocket, which answers pings.
+pub struct WsReceiver {
+    receiver: Receiver<WsStream>,
+    heartbeat: Arc<Heartbeat>,
+}
+
+impl WsReceiver {
+    /// Receives the next data message, skipping control frames.
+    pub async fn receive_data(&mut self, message: &mut Vec<u8>) -> Result<(), SockettoError> {
+        loop {
+            match self.receiver.receive(message).await? {
+                WsIncoming::Data(_) => return Ok(()),
+                WsIncoming::Pong(_) => (),
+                WsIncoming::Closed(_) => return Err(SockettoError::Closed),
+            }
+        }
+    }
+
+    pub fn heartbeat(&self) -> Arc<Heartbeat> {
+        Arc::clone(&self.heartbeat)
+    }
+}
+
+/// Follows the frames written to a websocket, so that a close frame is never written in the middle of another frame
+/// and nothing is written after one.
+#[derive(Default)]
+struct FrameTracker {
+    /// The header of the frame being written, until its payload length is known.
+    header: Vec<u8>,
+    /// How many payload bytes of the frame being written are still to come.
+    payload_left: u64,
+    closing: bool,
+    close_sent: bool,
+}
+
+impl FrameTracker {
+    fn track(&mut self, mut written: &[u8]) {
+        while !written.is_empty() {
+            if self.payload_left > 0 {
+                let len = self.payload_left.min(written.len() as u64);
+                self.payload_left -= len;
+                written = &written[len as usize..];
+            } else {
+                self.header.push(written[0]);
+                written = &written[1..];
+            }
+
+            // Server frames are unmasked, so their header only has the opcode, the length and an extended length
+            let header_len = match self.header.get(1).map(|b| b & 0x7f) {
+                None => 0,
+                Some(126) => 4,
+                Some(127) => 10,
+                Some(_) => 2,
+            };
+            if header_len != 0 && self.header.len() == header_len {
+                self.payload_left = match header_len {
+                    2 => u64::from(self.header[1] & 0x7f),
+                    4 => u64::from(u16::from_be_bytes([self.header[2], self.header[3]])),
+                    _ => u64::from_be_bytes(self.header[2..10].try_into().expect("length is 8 bytes")),
+                };
+                self.closing = self.header[0] & 0x0f == 0x8;
+                self.header.clear();
+            }
+            if self.closing && self.between_frames() {
+                self.close_sent = true;
+            }
+        }
+    }
+
+    fn between_frames(&self) -> bool {
+        self.header.is_empty() && self.payload_left == 0
+    }
+}
+
+struct Shared<T> {
+    io: T,
+    frames: FrameTracker,
+}
+
+/// A stream that can be written to from outside the soketto connection that owns it.
+pub struct SharedIo<T>(Arc<Mutex<Shared<T>>>);
+
+impl<T> Clone for SharedIo<T> {
+    fn clone(&self) -> Self {
+        SharedIo(Arc::clone(&self.0))
+    }
+}
+
+impl<T: FuturesRead + Unpin> FuturesRead for SharedIo<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        Pin::new(&mut self.0.lock().unwrap().io).poll_read(cx, buf)
+    }
+}
+
+impl<T: FuturesWrite + Unpin> FuturesWrite for SharedIo<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let shared = &mut *self.0.lock().unwrap();
+        // Nothing may follow a close frame, like the one soketto answers the client's close with after ours
+        if shared.frames.close_sent {
+            return Poll::Ready(Ok(buf.len()));
+        }
+        let result = Pin::new(&mut shared.io).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            shared.frames.track(&buf[..written]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.0.lock().unwrap().io).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.0.lock().unwrap().io).poll_close(cx)
+    }
+}
+
+/// Closes a websocket with a custom close code, which soketto does not support.
+///
+/// Sending is cancelled by dropping the relay future, which can leave a frame partially written. The close frame is
+/// then skipped, and so it is when soketto already answered a close frame from the client.
+pub struct WsCloser(SharedIo<BufWriter<HeartbeatIo<Compat<TokioIo<Upgraded>>>>>);
+
+impl WsCloser {
+    /// Sends a close frame without closing the underlying stream, so that the client can still send data until it answers.
+    pub async fn send_close(&self, code: u16, reason: &str) -> IoResult<()> {
+        {
+            let frames = &self.0.0.lock().unwrap().frames;
+            if frames.close_sent {
+                return Ok(());
+            }
+            if !frames.between_frames() {
+                return Err(IoError::other("a frame was left partially written"));
+            }
+        }
+
+        // Control frames cannot have more than 125 bytes of payload
+        let mut reason_len = reason.len().min(123);
+        while !reason.is_char_boundary(reason_len) {
+            reason_len -= 1;
+        }
+        let mut frame = vec![0x88, 2 + reason_len as u8]; // FIN + close opcode, unmasked as we are the server
+        frame.extend_from_slice(&code.to_be_bytes());
+        frame.extend_from_slice(&reason.as_bytes()[..reason_len]);
+
+        let mut io = self.0.clone();
+        io.write_all(&frame).await?;
+        io.flush().await
+    }
+
+    pub async fn close(mut self, code: u16, reason: &str) {
+        let sent = self.send_close(code, reason).await;
+        let closed = self.0.close().await;
+        if let Err(e) = sent.and(closed) {
+            debug!("Could not close websocket: {e}");
+        }
+    }
+}
+
+pub async fn handshake(server: Server, req: Request<Incoming>, max_message_size: Option<usize>, keepalive: Keepalive) -> Result<(WsSender, WsReceiver, WsCloser), BoxedError> {
+    // The negotiation to upgrade to a WebSocket connection has been successful so far. Next, we get back the underlying
+    // stream using `hyper::upgrade::on`, and hand this to a Soketto server to use to handle the WebSocket communication
+    // on this socket.
+    //
+    // Note: awaiting this won't succeed until the handshake response has been returned to the client, so this must be
+    // spawned on a separate task so as not to block that response being handed back.
+    let stream = hyper::upgrade::on(req).await?;
+    let heartbeat = Arc::new(Heartbeat { keepalive, last_seen: Mutex::new(Instant::now()), holds: AtomicUsize::new(0) });
+    let io = HeartbeatIo { io: TokioIo::new(stream).compat(), heartbeat: Arc::clone(&heartbeat), blocked: false };
+    let io = SharedIo(Arc::new(Mutex::new(Shared { io: BufWriter::new(io), frames: FrameTracker::default() })));
+    let closer = WsCloser(io.clone());
+    let stream = BufReader::new(io);
+
+    // Get back a reader and writer that we can use to send and receive websocket messages.
+    let mut builder = server.into_builder(stream);
+    if let Some(max_message_size) = max_message_size {
+        builder.set_max_message_size(max_message_size);
+    }
+    let (sender, receiver) = builder.finish();
+
+    // The first ping is sent after a full interval, as the client was just heard from
+    let pings = (!keepalive.ping_interval.is_zero()).then(|| {
+        let mut pings = tokio::time::interval_at((Instant::now() + keepalive.ping_interval).into(), keepalive.ping_interval);
+        pings.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        pings
+    });
+    Ok((WsSender { sender, pings }, WsReceiver { receiver, heartbeat }, closer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_tracker_follows_frame_boundaries() {
+        let mut frames = FrameTracker::default();
+
+        // A binary frame with a 16-bit extended length, written in pieces
+        let mut frame = vec![0x82, 126];
+        frame.extend_from_slice(&300u16.to_be_bytes());
+        frame.extend_from_slice(&[0; 300]);
+        for chunk in frame.chunks(7) {
+            assert!(!frames.close_sent);
+            frames.track(chunk);
+        }
+        assert!(frames.between_frames());
+
+        // A ping and the start of a frame with a 64-bit extended length
+        frames.track(&[0x89, 0, 0x82, 127]);
+        frames.track(&1u64.to_be_bytes());
+        assert!(!frames.between_frames());
+        frames.track(&[0]);
+        assert!(frames.between_frames());
+
+        // A close frame is only sent once its payload is
+        frames.track(&[0x88, 2, 0x03]);
+        assert!(!frames.close_sent);
+        frames.track(&[0xe8]);
+        assert!(frames.close_sent && frames.between_frames());
+    }
+}